@@ -0,0 +1,129 @@
+use crate::{constants, db, prompts};
+
+/// Trims a prompt and collapses its runs of spaces, so that trivially different requests share
+/// an answer. Line breaks and case are kept, as they can change the meaning, e.g. of a list to
+/// summarize, or of "US" and "us" in a text to translate.
+pub fn normalize(prompt: &str) -> String {
+    let mut normalized = String::with_capacity(prompt.len());
+    for c in prompt.trim().chars() {
+        if c != ' ' || !normalized.ends_with(' ') {
+            normalized.push(c);
+        }
+    }
+    normalized
+}
+
+/// A fresh enough answer to the same prompt of the same action, from the same model and prompts
+pub fn lookup(action: &str, prompt: &str, model: &str) -> Result<Option<String>, sqlite::Error> {
+    let db = db::get_db();
+    let mut statement = db.prepare(
+        "SELECT content FROM llm_cache
+        WHERE action = ? AND input = ? AND model = ? AND prompt_version = ?
+            AND created_at >= datetime('now', ?)",
+    )?;
+    let input = normalize(prompt);
+    statement.bind((1, action))?;
+    statement.bind((2, input.as_str()))?;
+    statement.bind((3, model))?;
+    statement.bind((4, prompts::version()))?;
+    statement.bind((5, format!("-{} hours", constants::CACHE_TTL_HOURS).as_str()))?;
+
+    let content = match statement.next()? {
+        sqlite::State::Row => statement.read::<String, _>("content")?,
+        sqlite::State::Done => return Ok(None),
+    };
+
+    let mut statement = db.prepare(
+        "UPDATE llm_cache SET hits = hits + 1, last_hit_at = CURRENT_TIMESTAMP
+        WHERE action = ? AND input = ? AND model = ? AND prompt_version = ?",
+    )?;
+    statement.bind((1, action))?;
+    statement.bind((2, input.as_str()))?;
+    statement.bind((3, model))?;
+    statement.bind((4, prompts::version()))?;
+    statement.next()?;
+    Ok(Some(content))
+}
+
+/// Saves an answer, replacing an older one to the same prompt, then drops the expired answers
+/// and the least recently used ones beyond `constants::CACHE_MAX_ENTRIES`
+pub fn store(action: &str, prompt: &str, model: &str, content: &str) -> Result<(), sqlite::Error> {
+    let db = db::get_db();
+    let mut statement = db.prepare(
+        "INSERT OR REPLACE INTO llm_cache (action, input, model, prompt_version, content)
+        VALUES (?, ?, ?, ?, ?)",
+    )?;
+    statement.bind((1, action))?;
+    statement.bind((2, normalize(prompt).as_str()))?;
+    statement.bind((3, model))?;
+    statement.bind((4, prompts::version()))?;
+    statement.bind((5, content))?;
+    statement.next()?;
+
+    let mut statement = db.prepare(
+        "DELETE FROM llm_cache WHERE created_at < datetime('now', ?)
+            OR rowid NOT IN (
+                SELECT rowid FROM llm_cache ORDER BY last_hit_at DESC, rowid DESC LIMIT ?
+            )",
+    )?;
+    statement.bind((1, format!("-{} hours", constants::CACHE_TTL_HOURS).as_str()))?;
+    statement.bind((2, constants::CACHE_MAX_ENTRIES))?;
+    statement.next()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count(model: &str) -> i64 {
+        let db = db::get_db();
+        let mut statement = db
+            .prepare("SELECT COUNT(*) AS n FROM llm_cache WHERE model = ?")
+            .unwrap();
+        statement.bind((1, model)).unwrap();
+        statement.next().unwrap();
+        statement.read::<i64, _>("n").unwrap()
+    }
+
+    #[test]
+    fn collapses_spaces_but_keeps_lines_and_case() {
+        assert_eq!(normalize("  define   US \n"), "define US");
+        assert_eq!(normalize("a\n\n-  b\n- c"), "a\n\n- b\n- c");
+        assert_ne!(normalize("us"), normalize("US"));
+    }
+
+    #[test]
+    fn serves_an_answer_to_the_same_prompt_and_model() {
+        store("define", "entropy  ", "cache-model", "disorder").unwrap();
+        assert_eq!(
+            lookup("define", " entropy", "cache-model")
+                .unwrap()
+                .as_deref(),
+            Some("disorder")
+        );
+        assert_eq!(lookup("explain", "entropy", "cache-model").unwrap(), None);
+        assert_eq!(lookup("define", "entropy", "other-model").unwrap(), None);
+    }
+
+    #[test]
+    fn drops_expired_answers() {
+        store("define", "enthalpy", "expiring-model", "heat").unwrap();
+        db::get_db()
+            .execute(format!(
+                "UPDATE llm_cache SET created_at = datetime('now', '-{} hours', '-1 minute')
+                WHERE model = 'expiring-model'",
+                constants::CACHE_TTL_HOURS
+            ))
+            .unwrap();
+        assert_eq!(
+            lookup("define", "enthalpy", "expiring-model").unwrap(),
+            None
+        );
+        assert_eq!(count("expiring-model"), 1);
+
+        // storing any answer purges the expired ones
+        store("define", "entropy", "fresh-model", "disorder").unwrap();
+        assert_eq!(count("expiring-model"), 0);
+    }
+}
@@ -1,3 +1,6 @@
+use std::time::Duration;
+
+/// Used when the prompt file sets no `system` prompt
 pub const SYSTEM_PROMPT: &str = "You are QuizPal, an AI-powered study helper inside a Telegram bot. 
 Your role is to support students in learning efficiently, with clear, accurate, and engaging answers. 
 Follow these principles:
@@ -38,6 +41,45 @@ Follow these principles:
 Goal: Help students learn smarter and retain knowledge long-term.
 ";
 
-pub const LLM_API_URL: &str = "https://api.groq.com/openai/v1/chat/completions";
+/// Used when config.toml sets no `base_url` for an OpenAI-compatible provider
+pub const LLM_BASE_URL: &str = "https://api.groq.com/openai/v1";
+
+pub const LLM_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const LLM_READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Minimum delay between two edits of a message streaming an answer, to stay below Telegram limits
+pub const STREAM_EDIT_INTERVAL: Duration = Duration::from_millis(1200);
+/// How often the "typing…" action is renewed while waiting for the first token
+pub const TYPING_INTERVAL: Duration = Duration::from_secs(4);
+/// Longest text Telegram accepts in a single message
+pub const MAX_MESSAGE_LENGTH: usize = 4096;
+
+/// Tokens of text sent in one summarization request; longer texts are summarized in chunks
+pub const SUMMARY_CHUNK_TOKENS: usize = 3000;
+/// Largest document `/summarize` downloads, in bytes
+pub const SUMMARY_MAX_DOCUMENT_SIZE: u32 = 512 * 1024;
+/// Tokens of earlier messages kept in a conversation, the oldest ones are dropped first
+pub const CONVERSATION_TOKEN_BUDGET: usize = 3000;
+/// Period covered by `/usage`
+pub const USAGE_REPORT_DAYS: u32 = 30;
+/// How long a cached answer is served, and how many answers the cache keeps at most
+pub const CACHE_TTL_HOURS: u32 = 7 * 24;
+pub const CACHE_MAX_ENTRIES: i64 = 2000;
 
 pub const MODEL: &str = "llama-3.3-70b-versatile";
+
+/// Audience of `/explain` when no `--level` is given
+pub const EXPLAIN_DEFAULT_LEVEL: &str = "beginner";
+
+/// Cards drawn by `/exam` when no count is given
+pub const EXAM_DEFAULT_QUESTIONS: usize = 10;
+
+/// Share of the maximum score needed to pass an exam
+pub const EXAM_PASS_THRESHOLD: f64 = 0.6;
+
+/// Questions generated by `/practice` when no count is given, and the most it accepts
+pub const PRACTICE_DEFAULT_QUESTIONS: usize = 5;
+pub const PRACTICE_MAX_QUESTIONS: usize = 20;
+
+/// Longest time limit of a question or of a sprint
+pub const MAX_TIME_LIMIT: Duration = Duration::from_secs(24 * 3600);
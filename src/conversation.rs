@@ -0,0 +1,198 @@
+use crate::llm::{estimate_tokens, ChatMessage};
+use crate::{constants, db};
+
+/// A conversation of a chat, named after the message that started it. Replying to one of its
+/// answers continues it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Thread(pub i64);
+
+/// The conversation the message replies to, if it replies to any part of an answer of the bot
+pub fn reply_thread(chat_id: i64, reply_to: i64) -> Result<Option<Thread>, sqlite::Error> {
+    let db = db::get_db();
+    let mut statement = db.prepare(
+        "SELECT conversation_messages.thread_id FROM conversation_parts
+        JOIN conversation_messages ON conversation_messages.id = conversation_parts.answer_id
+        WHERE conversation_parts.chat_id = ? AND conversation_parts.message_id = ?",
+    )?;
+    statement.bind((1, chat_id))?;
+    statement.bind((2, reply_to))?;
+
+    match statement.next()? {
+        sqlite::State::Row => Ok(Some(Thread(statement.read::<i64, _>("thread_id")?))),
+        sqlite::State::Done => Ok(None),
+    }
+}
+
+/// Earlier messages of a conversation, oldest first
+pub fn history(chat_id: i64, thread: Thread) -> Result<Vec<ChatMessage>, sqlite::Error> {
+    let db = db::get_db();
+    let mut statement = db.prepare(
+        "SELECT role, content FROM conversation_messages
+        WHERE chat_id = ? AND thread_id = ? ORDER BY id",
+    )?;
+    statement.bind((1, chat_id))?;
+    statement.bind((2, thread.0))?;
+
+    let mut messages = Vec::new();
+    while let sqlite::State::Row = statement.next()? {
+        let content = statement.read::<String, _>("content")?;
+        messages.push(match statement.read::<String, _>("role")?.as_str() {
+            "assistant" => ChatMessage::assistant(content),
+            _ => ChatMessage::user(content),
+        });
+    }
+    Ok(messages)
+}
+
+/// Stores a question and its answer, sent in the bot messages `message_ids`, then drops the
+/// oldest messages of the conversation beyond the token budget
+pub fn record(
+    chat_id: i64,
+    thread: Thread,
+    question: &str,
+    answer: &str,
+    message_ids: &[i64],
+) -> Result<(), sqlite::Error> {
+    let db = db::get_db();
+    let mut statement = db.prepare(
+        "INSERT INTO conversation_messages (chat_id, thread_id, role, content)
+        VALUES (?, ?, 'user', ?), (?, ?, 'assistant', ?)
+        RETURNING id, role",
+    )?;
+    statement.bind((1, chat_id))?;
+    statement.bind((2, thread.0))?;
+    statement.bind((3, question))?;
+    statement.bind((4, chat_id))?;
+    statement.bind((5, thread.0))?;
+    statement.bind((6, answer))?;
+    let mut answer_id = None;
+    while let sqlite::State::Row = statement.next()? {
+        if statement.read::<String, _>("role")? == "assistant" {
+            answer_id = Some(statement.read::<i64, _>("id")?);
+        }
+    }
+
+    // replying to any part of a long answer continues the conversation
+    if let Some(answer_id) = answer_id {
+        for message_id in message_ids {
+            let mut statement = db.prepare(
+                "INSERT OR REPLACE INTO conversation_parts (chat_id, message_id, answer_id)
+                VALUES (?, ?, ?)",
+            )?;
+            statement.bind((1, chat_id))?;
+            statement.bind((2, *message_id))?;
+            statement.bind((3, answer_id))?;
+            statement.next()?;
+        }
+    }
+
+    let mut statement = db.prepare(
+        "SELECT id, content FROM conversation_messages
+        WHERE chat_id = ? AND thread_id = ? ORDER BY id DESC",
+    )?;
+    statement.bind((1, chat_id))?;
+    statement.bind((2, thread.0))?;
+
+    // the latest answer is always kept, even when it is over the budget on its own
+    let mut tokens = 0;
+    let mut oldest_kept = None;
+    while let sqlite::State::Row = statement.next()? {
+        tokens += estimate_tokens(&statement.read::<String, _>("content")?);
+        if oldest_kept.is_some() && tokens > constants::CONVERSATION_TOKEN_BUDGET {
+            break;
+        }
+        oldest_kept = Some(statement.read::<i64, _>("id")?);
+    }
+
+    if let Some(oldest_kept) = oldest_kept {
+        let mut statement = db.prepare(
+            "DELETE FROM conversation_messages WHERE chat_id = ? AND thread_id = ? AND id < ?",
+        )?;
+        statement.bind((1, chat_id))?;
+        statement.bind((2, thread.0))?;
+        statement.bind((3, oldest_kept))?;
+        statement.next()?;
+
+        let mut statement = db.prepare(
+            "DELETE FROM conversation_parts WHERE chat_id = ? AND answer_id NOT IN (
+                SELECT id FROM conversation_messages WHERE chat_id = ?
+            )",
+        )?;
+        statement.bind((1, chat_id))?;
+        statement.bind((2, chat_id))?;
+        statement.next()?;
+    }
+    Ok(())
+}
+
+/// Deletes every conversation of a chat, returning the number of messages removed
+pub fn forget(chat_id: i64) -> Result<usize, sqlite::Error> {
+    let db = db::get_db();
+    let mut statement = db.prepare("DELETE FROM conversation_parts WHERE chat_id = ?")?;
+    statement.bind((1, chat_id))?;
+    statement.next()?;
+
+    let mut statement = db.prepare("DELETE FROM conversation_messages WHERE chat_id = ?")?;
+    statement.bind((1, chat_id))?;
+    statement.next()?;
+    Ok(db.change_count())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replying_to_any_part_continues_the_thread() {
+        let chat_id = 4501;
+        record(
+            chat_id,
+            Thread(10),
+            "What is DNA?",
+            "A molecule.",
+            &[11, 12],
+        )
+        .unwrap();
+
+        assert_eq!(reply_thread(chat_id, 11).unwrap(), Some(Thread(10)));
+        assert_eq!(reply_thread(chat_id, 12).unwrap(), Some(Thread(10)));
+        assert_eq!(reply_thread(chat_id, 10).unwrap(), None);
+        assert_eq!(reply_thread(chat_id + 1, 11).unwrap(), None);
+
+        let messages = history(chat_id, Thread(10)).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].content, "What is DNA?");
+        assert_eq!(messages[1].content, "A molecule.");
+    }
+
+    #[test]
+    fn drops_the_oldest_messages_beyond_the_budget() {
+        let chat_id = 4502;
+        let thread = Thread(20);
+        let long = "word ".repeat(constants::CONVERSATION_TOKEN_BUDGET);
+        record(chat_id, thread, "first", "first answer", &[21]).unwrap();
+        record(chat_id, thread, "second", &long, &[22]).unwrap();
+
+        // the latest answer is kept alone, even over the budget, with its parts
+        let messages = history(chat_id, thread).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].content, long);
+        assert_eq!(reply_thread(chat_id, 21).unwrap(), None);
+        assert_eq!(reply_thread(chat_id, 22).unwrap(), Some(thread));
+
+        record(chat_id, thread, "third", "short", &[23]).unwrap();
+        let messages = history(chat_id, thread).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].content, "third");
+    }
+
+    #[test]
+    fn forgets_every_thread_of_the_chat() {
+        let chat_id = 4503;
+        record(chat_id, Thread(30), "a", "b", &[31]).unwrap();
+        record(chat_id, Thread(32), "c", "d", &[33]).unwrap();
+        assert_eq!(forget(chat_id).unwrap(), 4);
+        assert_eq!(reply_thread(chat_id, 33).unwrap(), None);
+        assert!(history(chat_id, Thread(30)).unwrap().is_empty());
+    }
+}
@@ -3,13 +3,20 @@ use sqlite::Connection;
 use std::sync::Mutex;
 
 fn init_db() -> Connection {
-    let connection = sqlite::open("database.db").unwrap();
+    // every test run starts from an empty database of its own
+    let path = if cfg!(test) {
+        ":memory:"
+    } else {
+        "database.db"
+    };
+    let connection = sqlite::open(path).unwrap();
 
     let query = "
     CREATE TABLE IF NOT EXISTS users (
         id INTEGER PRIMARY KEY,        
         username TEXT,
-        joined_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        joined_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+        tier TEXT NOT NULL DEFAULT 'free'
     );
 
     CREATE TABLE IF NOT EXISTS flashcards (
@@ -20,6 +27,16 @@ fn init_db() -> Connection {
         topic TEXT NOT NULL,
         difficulty INTEGER DEFAULT 0,
         last_quiz_time DATE,
+        card_type TEXT NOT NULL DEFAULT 'text',
+        numeric_value REAL,
+        tolerance REAL,
+        tolerance_kind TEXT,
+        unit TEXT,
+        ease_factor REAL NOT NULL DEFAULT 2.5,
+        interval_days INTEGER NOT NULL DEFAULT 0,
+        repetitions INTEGER NOT NULL DEFAULT 0,
+        due_at TIMESTAMP,
+        choices TEXT,
         created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
         FOREIGN KEY (user_id) REFERENCES users(id)
     );
@@ -33,14 +50,168 @@ fn init_db() -> Connection {
         answered_questions INTEGER,
         taken_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
         is_over BOOLEAN,
+        mode TEXT NOT NULL DEFAULT 'typed',
+        skipped_questions INTEGER NOT NULL DEFAULT 0,
+        card_queue TEXT,
+        position INTEGER,
+        timed_out_questions INTEGER NOT NULL DEFAULT 0,
+        card_order TEXT NOT NULL DEFAULT 'difficulty',
+        state TEXT NOT NULL DEFAULT 'stopped',
+        time_limit_kind TEXT NOT NULL DEFAULT 'none',
+        time_limit_ms INTEGER,
+        time_left_ms INTEGER,
+        question_count INTEGER,
+        shuffle BOOLEAN NOT NULL DEFAULT 0,
         FOREIGN KEY (user_id) REFERENCES users(id)
     );
 
+    CREATE TABLE IF NOT EXISTS quiz_answers (
+        answer_id INTEGER PRIMARY KEY AUTOINCREMENT,
+        quiz_id INTEGER NOT NULL,
+        card_id INTEGER,
+        position INTEGER NOT NULL,
+        question TEXT NOT NULL,
+        reference_answer TEXT NOT NULL,
+        given_answer TEXT,
+        is_correct BOOLEAN,
+        skipped BOOLEAN NOT NULL DEFAULT 0,
+        credit REAL NOT NULL DEFAULT 0,
+        response_time_ms INTEGER,
+        hints_used INTEGER NOT NULL DEFAULT 0,
+        timed_out BOOLEAN NOT NULL DEFAULT 0,
+        choices TEXT,
+        answered_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+        FOREIGN KEY (quiz_id) REFERENCES quiz_reports(quiz_id),
+        FOREIGN KEY (card_id) REFERENCES flashcards(card_id)
+    );
+
+    CREATE TABLE IF NOT EXISTS llm_usage (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        user_id INTEGER,
+        action TEXT NOT NULL,
+        model TEXT NOT NULL,
+        prompt_tokens INTEGER NOT NULL,
+        completion_tokens INTEGER NOT NULL,
+        latency_ms INTEGER NOT NULL,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+    );
+
+    CREATE TABLE IF NOT EXISTS llm_cache (
+        action TEXT NOT NULL,
+        input TEXT NOT NULL,
+        model TEXT NOT NULL,
+        prompt_version TEXT NOT NULL,
+        content TEXT NOT NULL,
+        hits INTEGER NOT NULL DEFAULT 0,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+        last_hit_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+        PRIMARY KEY (action, input, model, prompt_version)
+    );
+
+    CREATE TABLE IF NOT EXISTS conversation_messages (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        chat_id INTEGER NOT NULL,
+        thread_id INTEGER NOT NULL,
+        role TEXT NOT NULL,
+        content TEXT NOT NULL,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+    );
+
+    CREATE TABLE IF NOT EXISTS conversation_parts (
+        chat_id INTEGER NOT NULL,
+        message_id INTEGER NOT NULL,
+        answer_id INTEGER NOT NULL,
+        PRIMARY KEY (chat_id, message_id)
+    );
+
     ";
     connection.execute(query).unwrap();
+
+    for (table, column, definition) in ADDED_COLUMNS {
+        add_column_if_missing(&connection, table, column, definition);
+    }
+
+    // reports from before the state column only have is_over, and those without a card queue
+    // cannot be resumed. The quizzes running when the bot stopped have lost their chat
+    // session, so they can only be resumed.
+    connection
+        .execute(
+            "
+            UPDATE quiz_reports SET state = 'finished' WHERE is_over = 1;
+            UPDATE quiz_reports SET state = 'finished' WHERE is_over IS NULL AND card_queue IS NULL;
+            UPDATE quiz_reports SET state = 'stopped' WHERE state = 'running';
+            ",
+        )
+        .unwrap();
+
     connection
 }
 
+// Columns introduced after the first release, added to databases created before them
+const ADDED_COLUMNS: &[(&str, &str, &str)] = &[
+    ("users", "tier", "TEXT NOT NULL DEFAULT 'free'"),
+    ("flashcards", "card_type", "TEXT NOT NULL DEFAULT 'text'"),
+    ("flashcards", "numeric_value", "REAL"),
+    ("flashcards", "tolerance", "REAL"),
+    ("flashcards", "tolerance_kind", "TEXT"),
+    ("flashcards", "unit", "TEXT"),
+    ("flashcards", "ease_factor", "REAL NOT NULL DEFAULT 2.5"),
+    ("flashcards", "interval_days", "INTEGER NOT NULL DEFAULT 0"),
+    ("flashcards", "repetitions", "INTEGER NOT NULL DEFAULT 0"),
+    ("flashcards", "due_at", "TIMESTAMP"),
+    ("flashcards", "choices", "TEXT"),
+    ("quiz_reports", "mode", "TEXT NOT NULL DEFAULT 'typed'"),
+    (
+        "quiz_reports",
+        "skipped_questions",
+        "INTEGER NOT NULL DEFAULT 0",
+    ),
+    ("quiz_reports", "card_queue", "TEXT"),
+    ("quiz_reports", "position", "INTEGER"),
+    (
+        "quiz_reports",
+        "timed_out_questions",
+        "INTEGER NOT NULL DEFAULT 0",
+    ),
+    (
+        "quiz_reports",
+        "card_order",
+        "TEXT NOT NULL DEFAULT 'difficulty'",
+    ),
+    ("quiz_reports", "state", "TEXT NOT NULL DEFAULT 'stopped'"),
+    (
+        "quiz_reports",
+        "time_limit_kind",
+        "TEXT NOT NULL DEFAULT 'none'",
+    ),
+    ("quiz_reports", "time_limit_ms", "INTEGER"),
+    ("quiz_reports", "time_left_ms", "INTEGER"),
+    ("quiz_reports", "question_count", "INTEGER"),
+    ("quiz_reports", "shuffle", "BOOLEAN NOT NULL DEFAULT 0"),
+    ("quiz_answers", "choices", "TEXT"),
+];
+
+fn add_column_if_missing(connection: &Connection, table: &str, column: &str, definition: &str) {
+    let mut exists = false;
+    connection
+        .iterate(format!("PRAGMA table_info({})", table), |pairs| {
+            exists |= pairs
+                .iter()
+                .any(|&(key, value)| key == "name" && value == Some(column));
+            true
+        })
+        .unwrap();
+
+    if !exists {
+        connection
+            .execute(format!(
+                "ALTER TABLE {} ADD COLUMN {} {}",
+                table, column, definition
+            ))
+            .unwrap();
+    }
+}
+
 // Singleton DB Access
 static DB_CONNECTION: Lazy<Mutex<Connection>> = Lazy::new(|| {
     let conn = init_db();
@@ -50,3 +221,10 @@ static DB_CONNECTION: Lazy<Mutex<Connection>> = Lazy::new(|| {
 pub fn get_db() -> std::sync::MutexGuard<'static, Connection> {
     DB_CONNECTION.lock().unwrap()
 }
+
+pub fn user_exists(user_id: u64) -> bool {
+    let db = get_db();
+    let mut statement = db.prepare("SELECT id FROM users WHERE id = ?").unwrap();
+    statement.bind((1, user_id.to_string().as_str())).unwrap();
+    matches!(statement.next(), Ok(sqlite::State::Row))
+}
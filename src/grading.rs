@@ -0,0 +1,260 @@
+use crate::types::{NumericSpec, Tolerance};
+use crate::units;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum QuantityError {
+    #[error("\"{0}\" is not a valid number")]
+    InvalidNumber(String),
+    #[error("unknown unit \"{0}\"")]
+    UnknownUnit(String),
+    #[error("\"{0}\" is not a valid tolerance, use e.g. 0.05 or 1%")]
+    InvalidTolerance(String),
+}
+
+// Resolves which of ',' and '.' is the decimal separator and drops the grouping one
+fn normalize_mantissa(mantissa: &str) -> String {
+    let commas = mantissa.matches(',').count();
+    let dots = mantissa.matches('.').count();
+
+    let decimal = match (commas, dots) {
+        (0, _) if dots > 1 => None,
+        (0, _) => Some('.'),
+        (1, 0) => Some(','),
+        (_, 0) => None,
+        _ => {
+            if mantissa.rfind(',') > mantissa.rfind('.') {
+                Some(',')
+            } else {
+                Some('.')
+            }
+        }
+    };
+
+    mantissa
+        .chars()
+        .filter_map(|c| match c {
+            ',' | '.' if Some(c) == decimal => Some('.'),
+            ',' | '.' => None,
+            _ => Some(c),
+        })
+        .collect()
+}
+
+/// Splits a leading number off `text` and returns it with the remaining unit text.
+/// Accepts "9.81", "9,81", "-1.2e-3", "6.02×10^23" and "6,02·10²³".
+pub fn parse_quantity(text: &str) -> Option<(f64, String)> {
+    let chars: Vec<char> = text.trim().replace('−', "-").chars().collect();
+    let mut i = 0;
+
+    let mut mantissa = String::new();
+    if i < chars.len() && (chars[i] == '-' || chars[i] == '+') {
+        mantissa.push(chars[i]);
+        i += 1;
+    }
+    while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.' || chars[i] == ',') {
+        mantissa.push(chars[i]);
+        i += 1;
+    }
+    if !mantissa.chars().any(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    // a trailing separator belongs to the sentence, not the number ("it is 9.81.")
+    while mantissa.ends_with(['.', ',']) {
+        mantissa.pop();
+        i -= 1;
+    }
+
+    let mut exponent = String::new();
+    if i < chars.len() && (chars[i] == 'e' || chars[i] == 'E') {
+        let mut j = i + 1;
+        let mut digits = String::new();
+        if j < chars.len() && (chars[j] == '-' || chars[j] == '+') {
+            digits.push(chars[j]);
+            j += 1;
+        }
+        let start = j;
+        while j < chars.len() && chars[j].is_ascii_digit() {
+            digits.push(chars[j]);
+            j += 1;
+        }
+        if j > start {
+            exponent = digits;
+            i = j;
+        }
+    } else {
+        let mut j = i;
+        while j < chars.len() && chars[j] == ' ' {
+            j += 1;
+        }
+        if j < chars.len() && matches!(chars[j], '×' | 'x' | '*' | '·') {
+            j += 1;
+            while j < chars.len() && chars[j] == ' ' {
+                j += 1;
+            }
+            if chars[j..].starts_with(&['1', '0']) {
+                j += 2;
+                if j < chars.len() && chars[j] == '^' {
+                    j += 1;
+                }
+                let mut digits = String::new();
+                while j < chars.len() {
+                    if let Some(d) = units::superscript_to_ascii(chars[j]) {
+                        digits.push(d);
+                    } else if chars[j].is_ascii_digit() || (digits.is_empty() && chars[j] == '-') {
+                        digits.push(chars[j]);
+                    } else {
+                        break;
+                    }
+                    j += 1;
+                }
+                if digits.chars().any(|c| c.is_ascii_digit()) {
+                    exponent = digits;
+                    i = j;
+                }
+            }
+        }
+    }
+
+    let mut number = normalize_mantissa(&mantissa);
+    if !exponent.is_empty() {
+        number.push('e');
+        number.push_str(&exponent);
+    }
+
+    let value = number.parse::<f64>().ok()?;
+    let rest: String = chars[i..].iter().collect();
+
+    Some((value, rest.trim().trim_end_matches('.').trim().to_string()))
+}
+
+/// Parses a tolerance such as "1%" (relative) or "0.05" (absolute)
+pub fn parse_tolerance(text: &str) -> Result<Tolerance, QuantityError> {
+    let text = text.trim().trim_start_matches('±');
+    let invalid = || QuantityError::InvalidTolerance(text.to_string());
+
+    let (value, rest) = parse_quantity(text).ok_or_else(invalid)?;
+    if value < 0.0 {
+        return Err(invalid());
+    }
+
+    match rest.as_str() {
+        "%" => Ok(Tolerance::Relative(value / 100.0)),
+        "" => Ok(Tolerance::Absolute(value)),
+        _ => Err(invalid()),
+    }
+}
+
+/// Parses the reference answer of a numeric card, e.g. "9.81 m/s^2"
+pub fn parse_reference(text: &str) -> Result<(f64, Option<String>), QuantityError> {
+    let (value, unit) =
+        parse_quantity(text).ok_or_else(|| QuantityError::InvalidNumber(text.to_string()))?;
+
+    if unit.is_empty() {
+        return Ok((value, None));
+    }
+    if units::parse_unit(&unit).is_none() {
+        return Err(QuantityError::UnknownUnit(unit));
+    }
+    Ok((value, Some(unit)))
+}
+
+/// Grades a free-text answer against a numeric card, converting compatible units.
+/// An answer without a unit is assumed to be expressed in the reference unit.
+pub fn check_numeric(input: &str, spec: &NumericSpec) -> bool {
+    let (mut value, unit) = match parse_quantity(input) {
+        Some(q) => q,
+        None => return false,
+    };
+
+    if !unit.is_empty() {
+        let given = match units::parse_unit(&unit) {
+            Some(u) => u,
+            None => return false,
+        };
+        let reference = match &spec.unit {
+            Some(u) => units::parse_unit(u).unwrap_or_else(units::Unit::dimensionless),
+            None => units::Unit::dimensionless(),
+        };
+        value = match given.convert(value, &reference) {
+            Some(v) => v,
+            None => return false,
+        };
+    }
+
+    // slack for the rounding error introduced by unit conversion
+    let difference = (value - spec.value).abs() - 1e-9 * spec.value.abs();
+    match spec.tolerance {
+        Tolerance::Relative(r) => difference <= r * spec.value.abs(),
+        Tolerance::Absolute(a) => difference <= a,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(value: f64, tolerance: Tolerance, unit: Option<&str>) -> NumericSpec {
+        NumericSpec {
+            value,
+            tolerance,
+            unit: unit.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn parses_numbers_in_every_notation() {
+        assert_eq!(parse_quantity("9.81"), Some((9.81, String::new())));
+        assert_eq!(parse_quantity("9,81"), Some((9.81, String::new())));
+        assert_eq!(parse_quantity("1,234.5"), Some((1234.5, String::new())));
+        assert_eq!(parse_quantity("1.234,5"), Some((1234.5, String::new())));
+        assert_eq!(
+            parse_quantity("−1.2e-3 m"),
+            Some((-1.2e-3, String::from("m")))
+        );
+        assert_eq!(parse_quantity("6.02×10^23"), Some((6.02e23, String::new())));
+        assert_eq!(
+            parse_quantity("6,02·10²³ mol"),
+            Some((6.02e23, String::from("mol")))
+        );
+    }
+
+    #[test]
+    fn keeps_the_unit_and_drops_the_final_period() {
+        assert_eq!(parse_quantity("it is 9.81"), None);
+        assert_eq!(parse_quantity("9.81."), Some((9.81, String::new())));
+        assert_eq!(
+            parse_quantity("9.81 m/s^2."),
+            Some((9.81, String::from("m/s^2")))
+        );
+    }
+
+    #[test]
+    fn parses_tolerances() {
+        assert!(matches!(parse_tolerance("1%"), Ok(Tolerance::Relative(r)) if r == 0.01));
+        assert!(matches!(parse_tolerance("±0.05"), Ok(Tolerance::Absolute(a)) if a == 0.05));
+        assert!(parse_tolerance("-1").is_err());
+        assert!(parse_tolerance("1 m").is_err());
+    }
+
+    #[test]
+    fn grades_within_the_tolerance() {
+        let gravity = spec(9.81, Tolerance::Relative(0.01), Some("m/s^2"));
+        assert!(check_numeric("9.81", &gravity));
+        assert!(check_numeric("9,8 m/s²", &gravity));
+        assert!(!check_numeric("9.5", &gravity));
+        assert!(!check_numeric("about ten", &gravity));
+
+        let boiling = spec(100.0, Tolerance::Absolute(0.5), None);
+        assert!(check_numeric("100.4", &boiling));
+        assert!(!check_numeric("101", &boiling));
+    }
+
+    #[test]
+    fn converts_compatible_units_before_grading() {
+        let speed = spec(10.0, Tolerance::Absolute(0.0), Some("m/s"));
+        assert!(check_numeric("36 km/h", &speed));
+        assert!(!check_numeric("36 km", &speed));
+        assert!(!check_numeric("36 furlongs", &speed));
+    }
+}
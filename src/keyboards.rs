@@ -0,0 +1,73 @@
+use crate::scheduling::Rating;
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup};
+
+/// Actions encoded in the data of inline keyboard buttons
+pub enum CallbackAction {
+    RecallShow,
+    RecallRate(Rating),
+    /// Asks the model to explain the logged wrong answer with the given id
+    Why(i64),
+    /// Saves the questions missed in the practice quiz with the given id as flashcards
+    SaveMissed(i64),
+    /// Judges the true/false statement asked at the given position
+    TrueFalse {
+        position: usize,
+        value: bool,
+    },
+}
+
+impl CallbackAction {
+    pub fn parse(data: &str) -> Option<CallbackAction> {
+        let (scope, value) = data.split_once(':')?;
+        match (scope, value) {
+            ("recall", "show") => Some(CallbackAction::RecallShow),
+            ("recall", rating) => Rating::from_key(rating).map(CallbackAction::RecallRate),
+            ("why", index) => index.parse().ok().map(CallbackAction::Why),
+            ("save", quiz_id) => quiz_id.parse().ok().map(CallbackAction::SaveMissed),
+            ("tf", answer) => {
+                let (position, value) = answer.split_once(':')?;
+                Some(CallbackAction::TrueFalse {
+                    position: position.parse().ok()?,
+                    value: value.parse().ok()?,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+pub fn recall_reveal() -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(vec![vec![InlineKeyboardButton::callback(
+        "👀 Show answer",
+        "recall:show",
+    )]])
+}
+
+pub fn recall_rating() -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(vec![Rating::ALL
+        .into_iter()
+        .map(|r| InlineKeyboardButton::callback(r.label(), format!("recall:{}", r.key())))
+        .collect::<Vec<_>>()])
+}
+
+pub fn why(answer_id: i64) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(vec![vec![InlineKeyboardButton::callback(
+        "🤔 Why?",
+        format!("why:{}", answer_id),
+    )]])
+}
+
+pub fn save_missed(quiz_id: i64) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(vec![vec![InlineKeyboardButton::callback(
+        "💾 Save missed questions as flashcards",
+        format!("save:{}", quiz_id),
+    )]])
+}
+
+// The position keeps a late press from answering the next statement
+pub fn true_false(position: usize) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(vec![vec![
+        InlineKeyboardButton::callback("✅ True", format!("tf:{}:true", position)),
+        InlineKeyboardButton::callback("❌ False", format!("tf:{}:false", position)),
+    ]])
+}
@@ -0,0 +1,278 @@
+use crate::db;
+
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Tier of the users without one in the database
+pub const DEFAULT_TIER: &str = "free";
+
+#[derive(Debug, Error, PartialEq)]
+pub enum LimitError {
+    #[error("⏳ Slow down a little, you can ask again in {} s.", .0.as_secs_f64().ceil().max(1.0))]
+    UserRateLimited(Duration),
+    #[error("⏳ This chat is sending too many requests, please try again in {} s.", .0.as_secs_f64().ceil().max(1.0))]
+    ChatRateLimited(Duration),
+    #[error("🔋 You reached your daily AI quota, it resets at 00:00 UTC.")]
+    QuotaReached,
+}
+
+/// The `[limits]` section of config.toml
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// Requests per minute shared by all the users of a group
+    pub chat_requests_per_minute: u32,
+    /// Limits per tier, the `free` one applies to everyone without a tier
+    pub tiers: HashMap<String, TierLimits>,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            chat_requests_per_minute: 20,
+            tiers: HashMap::new(),
+        }
+    }
+}
+
+/// An entry of `[limits.tiers]`, e.g. `[limits.tiers.pro]`
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct TierLimits {
+    /// Requests refilled every minute, and the most that can be sent in a burst
+    #[serde(default = "default_requests_per_minute")]
+    pub requests_per_minute: u32,
+    /// Tokens a user can spend per day, unlimited when unset
+    #[serde(default)]
+    pub daily_tokens: Option<u64>,
+}
+
+fn default_requests_per_minute() -> u32 {
+    TierLimits::default().requests_per_minute
+}
+
+impl Default for TierLimits {
+    fn default() -> Self {
+        TierLimits {
+            requests_per_minute: 6,
+            daily_tokens: Some(100_000),
+        }
+    }
+}
+
+impl LimitsConfig {
+    pub fn is_tier(&self, tier: &str) -> bool {
+        tier == DEFAULT_TIER || self.tiers.contains_key(tier)
+    }
+
+    pub fn tier(&self, tier: &str) -> TierLimits {
+        match self
+            .tiers
+            .get(tier)
+            .or_else(|| self.tiers.get(DEFAULT_TIER))
+        {
+            Some(limits) => *limits,
+            None => TierLimits::default(),
+        }
+    }
+}
+
+// Refills continuously up to its capacity, one token per request
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(capacity: u32, now: Instant) -> Self {
+        TokenBucket {
+            tokens: capacity as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, per_minute: u32, now: Instant) {
+        let refilled = now.duration_since(self.updated).as_secs_f64() * per_minute as f64 / 60.0;
+        self.tokens = (self.tokens + refilled).min(per_minute as f64);
+        self.updated = now;
+    }
+
+    // How long until a request can be sent, zero if one can be sent now
+    fn wait(&self, per_minute: u32) -> Duration {
+        if self.tokens >= 1.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64((1.0 - self.tokens) * 60.0 / per_minute.max(1) as f64)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum BucketKey {
+    User(u64),
+    Chat(i64),
+}
+
+static BUCKETS: Lazy<Mutex<HashMap<BucketKey, TokenBucket>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// A bucket refills completely within a minute, after which it is no different from a new one
+const FULL_REFILL: Duration = Duration::from_secs(60);
+
+/// Fails once `user_id` has spent the daily tokens of their tier
+pub fn check_quota(config: &LimitsConfig, user_id: u64) -> Result<(), LimitError> {
+    within_quota(config.tier(&user_tier(user_id)), user_id)
+}
+
+fn within_quota(limits: TierLimits, user_id: u64) -> Result<(), LimitError> {
+    let Some(quota) = limits.daily_tokens else {
+        return Ok(());
+    };
+    // a failing lookup should not lock everyone out
+    match tokens_used_today(user_id) {
+        Ok(used) if used >= quota => Err(LimitError::QuotaReached),
+        Ok(_) => Ok(()),
+        Err(e) => {
+            warn!("failed to load the usage of {}: {}", user_id, e);
+            Ok(())
+        }
+    }
+}
+
+/// Lets a request of `user_id` through if the user is within their daily quota and neither
+/// the user nor, for groups, `group_id` is sending requests too fast
+pub fn admit(config: &LimitsConfig, user_id: u64, group_id: Option<i64>) -> Result<(), LimitError> {
+    let limits = config.tier(&user_tier(user_id));
+    within_quota(limits, user_id)?;
+
+    let now = Instant::now();
+    let mut buckets = BUCKETS.lock().unwrap();
+    buckets.retain(|_, bucket| now.duration_since(bucket.updated) < FULL_REFILL);
+
+    let user = BucketKey::User(user_id);
+    let user_rate = limits.requests_per_minute;
+    let bucket = buckets
+        .entry(user)
+        .or_insert_with(|| TokenBucket::new(user_rate, now));
+    bucket.refill(user_rate, now);
+    let wait = bucket.wait(user_rate);
+    if !wait.is_zero() {
+        return Err(LimitError::UserRateLimited(wait));
+    }
+
+    // the user is only charged once the chat has room too
+    if let Some(group_id) = group_id {
+        let chat_rate = config.chat_requests_per_minute;
+        let bucket = buckets
+            .entry(BucketKey::Chat(group_id))
+            .or_insert_with(|| TokenBucket::new(chat_rate, now));
+        bucket.refill(chat_rate, now);
+        let wait = bucket.wait(chat_rate);
+        if !wait.is_zero() {
+            return Err(LimitError::ChatRateLimited(wait));
+        }
+        bucket.tokens -= 1.0;
+    }
+    if let Some(bucket) = buckets.get_mut(&user) {
+        bucket.tokens -= 1.0;
+    }
+    Ok(())
+}
+
+/// Tier of a user as set by an admin, `DEFAULT_TIER` when unset or unregistered
+pub fn user_tier(user_id: u64) -> String {
+    let db = db::get_db();
+    let mut statement = db.prepare("SELECT tier FROM users WHERE id = ?").unwrap();
+    statement.bind((1, user_id.to_string().as_str())).unwrap();
+    match statement.next() {
+        Ok(sqlite::State::Row) => statement
+            .read::<String, _>("tier")
+            .unwrap_or_else(|_| DEFAULT_TIER.to_string()),
+        _ => DEFAULT_TIER.to_string(),
+    }
+}
+
+/// Moves a registered user to another tier, returning false if the user is unknown
+pub fn set_user_tier(user_id: u64, tier: &str) -> Result<bool, sqlite::Error> {
+    let db = db::get_db();
+    let mut statement = db.prepare("UPDATE users SET tier = ? WHERE id = ?")?;
+    statement.bind((1, tier))?;
+    statement.bind((2, user_id.to_string().as_str()))?;
+    statement.next()?;
+    Ok(db.change_count() > 0)
+}
+
+// Tokens spent by a user since midnight UTC, the timezone of the usage timestamps
+fn tokens_used_today(user_id: u64) -> Result<u64, sqlite::Error> {
+    let db = db::get_db();
+    let mut statement = db.prepare(
+        "SELECT COALESCE(SUM(prompt_tokens + completion_tokens), 0) AS tokens FROM llm_usage
+        WHERE user_id = ? AND created_at >= date('now')",
+    )?;
+    statement.bind((1, user_id.to_string().as_str()))?;
+    statement.next()?;
+    Ok(statement.read::<i64, _>("tokens")? as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_a_burst_up_to_the_capacity() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(6, now);
+        for _ in 0..6 {
+            bucket.refill(6, now);
+            assert_eq!(bucket.wait(6), Duration::ZERO);
+            bucket.tokens -= 1.0;
+        }
+        bucket.refill(6, now);
+        assert_eq!(bucket.wait(6), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(6, now);
+        bucket.tokens = 0.0;
+
+        bucket.refill(6, now + Duration::from_secs(5));
+        assert!((bucket.wait(6).as_secs_f64() - 5.0).abs() < 1e-9);
+        bucket.refill(6, now + Duration::from_secs(10));
+        assert_eq!(bucket.wait(6), Duration::ZERO);
+    }
+
+    #[test]
+    fn never_refills_past_the_capacity() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(6, now);
+        bucket.refill(6, now + FULL_REFILL * 10);
+        assert_eq!(bucket.tokens, 6.0);
+    }
+
+    #[test]
+    fn falls_back_to_the_free_tier() {
+        let config: LimitsConfig = toml::from_str(
+            "
+            [tiers.free]
+            requests_per_minute = 3
+            [tiers.pro]
+            requests_per_minute = 30
+            daily_tokens = 1000000
+            ",
+        )
+        .unwrap();
+        assert_eq!(config.tier("pro").requests_per_minute, 30);
+        assert_eq!(config.tier("free").requests_per_minute, 3);
+        assert_eq!(config.tier("gone").requests_per_minute, 3);
+        assert_eq!(config.tier("free").daily_tokens, None);
+        assert!(config.is_tier("free") && !config.is_tier("gone"));
+
+        let unset = LimitsConfig::default().tier(DEFAULT_TIER);
+        assert_eq!(unset.requests_per_minute, 6);
+        assert_eq!(unset.daily_tokens, Some(100_000));
+    }
+}
@@ -0,0 +1,560 @@
+use crate::constants;
+use crate::requests::{extract_answer, ExtractError, ModelAnswer, RequestError, Usage};
+
+use once_cell::sync::Lazy;
+use rand::Rng;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::UnboundedSender;
+
+pub type LlmFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<ModelAnswer, RequestError>> + Send + 'a>>;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChatMessage {
+    pub role: &'static str,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn system(content: impl Into<String>) -> Self {
+        ChatMessage {
+            role: "system",
+            content: content.into(),
+        }
+    }
+
+    pub fn user(content: impl Into<String>) -> Self {
+        ChatMessage {
+            role: "user",
+            content: content.into(),
+        }
+    }
+
+    pub fn assistant(content: impl Into<String>) -> Self {
+        ChatMessage {
+            role: "assistant",
+            content: content.into(),
+        }
+    }
+}
+
+/// Rough token count of a text, about four characters per token for English prose
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// Model and sampling settings of a single completion
+#[derive(Debug, Clone, PartialEq)]
+pub struct GenerationParams {
+    pub model: String,
+    pub temperature: f64,
+    pub max_tokens: u32,
+    /// `response_format` of the request, e.g. `{"type": "json_object"}` for JSON replies
+    pub response_format: Option<serde_json::Value>,
+}
+
+/// What a streamed answer sends while it is generated
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamEvent {
+    /// The next piece of the answer
+    Delta(String),
+    /// The pieces sent so far are to be dropped, another model answers from the start
+    Restart,
+}
+
+/// A backend able to answer a chat conversation
+pub trait LlmProvider: Send + Sync {
+    fn complete<'a>(
+        &'a self,
+        messages: &'a [ChatMessage],
+        params: &'a GenerationParams,
+    ) -> LlmFuture<'a>;
+
+    /// Sends the answer piece by piece to `on_delta` while it is generated, then returns it whole.
+    /// Backends without streaming send the complete answer at once.
+    fn stream<'a>(
+        &'a self,
+        messages: &'a [ChatMessage],
+        params: &'a GenerationParams,
+        on_delta: UnboundedSender<StreamEvent>,
+    ) -> LlmFuture<'a> {
+        Box::pin(async move {
+            let answer = self.complete(messages, params).await?;
+            // the receiver may be gone already, the answer is returned anyway
+            let _ = on_delta.send(StreamEvent::Delta(answer.content.clone()));
+            Ok(answer)
+        })
+    }
+}
+
+/// What a line of a server-sent event stream carries
+#[derive(Debug, PartialEq)]
+pub enum SseEvent {
+    Delta {
+        id: Option<String>,
+        content: String,
+        usage: Option<Usage>,
+    },
+    Done,
+}
+
+/// Parses one line of an OpenAI-compatible completion stream, ignoring anything but data lines
+pub fn parse_sse_line(line: &str) -> Option<SseEvent> {
+    let data = line.trim().strip_prefix("data:")?.trim();
+    if data == "[DONE]" {
+        return Some(SseEvent::Done);
+    }
+
+    let chunk: serde_json::Value = serde_json::from_str(data).ok()?;
+    let content = chunk
+        .get("choices")
+        .and_then(|c| c.get(0))
+        .and_then(|c| c.get("delta"))
+        .and_then(|d| d.get("content"))
+        .and_then(|c| c.as_str())
+        .unwrap_or_default();
+
+    Some(SseEvent::Delta {
+        id: chunk.get("id").and_then(|i| i.as_str()).map(str::to_string),
+        content: content.to_string(),
+        usage: Usage::from_json(&chunk),
+    })
+}
+
+static HTTP_CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .connect_timeout(constants::LLM_CONNECT_TIMEOUT)
+        .read_timeout(constants::LLM_READ_TIMEOUT)
+        .build()
+        .expect("Failed to build the HTTP client")
+});
+
+// Attempts on a rate limited or failing model before giving up on it
+const MAX_ATTEMPTS: u32 = 4;
+const BASE_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(20);
+// Longest Retry-After waited for, a server asking for more is given up on
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+/// Whether the server is rate limiting or failing, rather than refusing the request itself
+pub fn is_retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+// The Retry-After header of a response, in seconds
+fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+}
+
+// Waits as long as the server asks with Retry-After, or backs off exponentially with jitter.
+// None when the server asks to wait longer than `MAX_RETRY_AFTER`.
+fn retry_delay(retry_after: Option<Duration>, attempt: u32) -> Option<Duration> {
+    match retry_after {
+        Some(delay) if delay > MAX_RETRY_AFTER => None,
+        Some(delay) => Some(delay),
+        None => {
+            let exponential = BASE_BACKOFF.saturating_mul(2u32.saturating_pow(attempt));
+            let jitter = rand::rng().random_range(0..=BASE_BACKOFF.as_millis() as u64);
+            Some((exponential + Duration::from_millis(jitter)).min(MAX_BACKOFF))
+        }
+    }
+}
+
+/// Any server implementing the OpenAI chat completions API: Groq, OpenAI, Ollama, llama.cpp...
+pub struct OpenAiCompatible {
+    pub base_url: String,
+    pub api_key: Option<String>,
+}
+
+impl OpenAiCompatible {
+    // Sends the request, retrying while the server is rate limiting or failing
+    async fn send(
+        &self,
+        messages: &[ChatMessage],
+        params: &GenerationParams,
+        stream: bool,
+    ) -> Result<reqwest::Response, RequestError> {
+        let mut body = serde_json::json!({
+          "messages": messages,
+          "model": params.model,
+          "temperature": params.temperature,
+          "max_completion_tokens": params.max_tokens,
+          "top_p": 1,
+          "stream": stream,
+          "stop": null
+        });
+        if stream {
+            // the usage is only sent in the last chunk of a stream when asked for
+            body["stream_options"] = serde_json::json!({ "include_usage": true });
+        }
+        if let Some(format) = &params.response_format {
+            body["response_format"] = format.clone();
+        }
+
+        let mut attempt = 0;
+        let response = loop {
+            let mut request = HTTP_CLIENT
+                .post(format!(
+                    "{}/chat/completions",
+                    self.base_url.trim_end_matches('/')
+                ))
+                .json(&body);
+            // local servers usually run without authentication
+            if let Some(key) = &self.api_key {
+                request = request.header("Authorization", format!("Bearer {}", key));
+            }
+
+            let response = request.send().await?;
+            attempt += 1;
+            if !is_retryable(response.status()) || attempt >= MAX_ATTEMPTS {
+                break response;
+            }
+
+            let Some(delay) = retry_delay(retry_after(&response), attempt - 1) else {
+                warn!(
+                    "model {} answered {} and asks to wait too long, giving up",
+                    params.model,
+                    response.status()
+                );
+                break response;
+            };
+            warn!(
+                "model {} answered {}, retrying in {:?}",
+                params.model,
+                response.status(),
+                delay
+            );
+            tokio::time::sleep(delay).await;
+        };
+
+        // remaining errors are returned, so the next model can be tried
+        Ok(response.error_for_status()?)
+    }
+}
+
+impl LlmProvider for OpenAiCompatible {
+    fn complete<'a>(
+        &'a self,
+        messages: &'a [ChatMessage],
+        params: &'a GenerationParams,
+    ) -> LlmFuture<'a> {
+        Box::pin(async move {
+            let response: serde_json::Value =
+                self.send(messages, params, false).await?.json().await?;
+            extract_answer(&response).map_err(RequestError::from)
+        })
+    }
+
+    fn stream<'a>(
+        &'a self,
+        messages: &'a [ChatMessage],
+        params: &'a GenerationParams,
+        on_delta: UnboundedSender<StreamEvent>,
+    ) -> LlmFuture<'a> {
+        Box::pin(async move {
+            let mut response = self.send(messages, params, true).await?;
+
+            let mut id = None;
+            let mut usage = None;
+            let mut content = String::new();
+            // bytes are kept until a full line is received, a chunk can end mid-character
+            let mut pending: Vec<u8> = Vec::new();
+            'chunks: while let Some(chunk) = response.chunk().await? {
+                pending.extend_from_slice(&chunk);
+                while let Some(end) = pending.iter().position(|b| *b == b'\n') {
+                    let line: Vec<u8> = pending.drain(..=end).collect();
+                    match parse_sse_line(&String::from_utf8_lossy(&line)) {
+                        Some(SseEvent::Delta {
+                            id: chunk_id,
+                            content: delta,
+                            usage: chunk_usage,
+                        }) => {
+                            id = id.or(chunk_id);
+                            usage = usage.or(chunk_usage);
+                            if !delta.is_empty() {
+                                content.push_str(&delta);
+                                let _ = on_delta.send(StreamEvent::Delta(delta));
+                            }
+                        }
+                        Some(SseEvent::Done) => break 'chunks,
+                        None => {}
+                    }
+                }
+            }
+
+            Ok(ModelAnswer {
+                id: id.ok_or(ExtractError::MissingField)?,
+                content,
+                usage,
+            })
+        })
+    }
+}
+
+/// Answers without any network access, always the same way for the same conversation
+pub struct MockProvider {
+    /// Fixed reply, the last user message is echoed when unset
+    pub reply: Option<String>,
+}
+
+impl LlmProvider for MockProvider {
+    fn complete<'a>(
+        &'a self,
+        messages: &'a [ChatMessage],
+        _params: &'a GenerationParams,
+    ) -> LlmFuture<'a> {
+        Box::pin(async move {
+            let content = match &self.reply {
+                Some(reply) => reply.clone(),
+                None => {
+                    let prompt = messages
+                        .iter()
+                        .rev()
+                        .find(|m| m.role == "user")
+                        .map(|m| m.content.as_str())
+                        .unwrap_or_default();
+                    format!("[mock] {}", prompt)
+                }
+            };
+            Ok(ModelAnswer {
+                id: String::from("mock"),
+                content,
+                usage: None,
+            })
+        })
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderKind {
+    #[default]
+    OpenAi,
+    Mock,
+}
+
+/// The `[llm]` section of config.toml
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct LlmConfig {
+    pub provider: ProviderKind,
+    /// Base URL of the API, e.g. http://localhost:11434/v1 for Ollama
+    pub base_url: Option<String>,
+    /// Model of the actions without their own, `constants::MODEL` when unset
+    pub model: Option<String>,
+    /// Tried in order after the models of an action have failed
+    pub fallback_models: Vec<String>,
+    /// Overrides the top-level `llm_key`
+    pub api_key: Option<String>,
+    pub mock_reply: Option<String>,
+    /// Settings per action, keyed by `Actions::key`
+    pub actions: HashMap<String, ActionConfig>,
+    /// Prices per model, to estimate the cost of the usage
+    pub prices: HashMap<String, ModelPrice>,
+}
+
+/// An entry of `[llm.prices]`, in dollars per million tokens
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct ModelPrice {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+impl ModelPrice {
+    pub fn cost(&self, prompt_tokens: u64, completion_tokens: u64) -> f64 {
+        (prompt_tokens as f64 * self.prompt + completion_tokens as f64 * self.completion)
+            / 1_000_000.0
+    }
+}
+
+/// An entry of `[llm.actions]`, e.g. `[llm.actions.define]`
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct ActionConfig {
+    /// Models to try in order
+    pub models: Vec<String>,
+    pub temperature: Option<f64>,
+    pub max_tokens: Option<u32>,
+}
+
+const DEFAULT_TEMPERATURE: f64 = 1.0;
+const DEFAULT_MAX_TOKENS: u32 = 1024;
+
+impl LlmConfig {
+    /// Builds the configured provider; `llm_key` is the top-level key of config.toml
+    pub fn provider(&self, llm_key: Option<&str>) -> Box<dyn LlmProvider> {
+        match self.provider {
+            ProviderKind::OpenAi => Box::new(OpenAiCompatible {
+                base_url: self
+                    .base_url
+                    .clone()
+                    .unwrap_or_else(|| constants::LLM_BASE_URL.to_string()),
+                api_key: self
+                    .api_key
+                    .as_deref()
+                    .or(llm_key)
+                    .filter(|key| !key.is_empty())
+                    .map(str::to_string),
+            }),
+            ProviderKind::Mock => Box::new(MockProvider {
+                reply: self.mock_reply.clone(),
+            }),
+        }
+    }
+
+    /// Settings to try in order for an action: its own models, then the fallback ones
+    pub fn route(&self, action: &str) -> Vec<GenerationParams> {
+        let settings = self.actions.get(action).cloned().unwrap_or_default();
+
+        let mut models: Vec<String> = if settings.models.is_empty() {
+            vec![self
+                .model
+                .clone()
+                .unwrap_or_else(|| constants::MODEL.to_string())]
+        } else {
+            settings.models
+        };
+        for model in &self.fallback_models {
+            if !models.contains(model) {
+                models.push(model.clone());
+            }
+        }
+
+        models
+            .into_iter()
+            .map(|model| GenerationParams {
+                model,
+                temperature: settings.temperature.unwrap_or(DEFAULT_TEMPERATURE),
+                max_tokens: settings.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+                response_format: None,
+            })
+            .collect()
+    }
+}
+
+/// Stops calling the provider for a while after repeated failures, so that users get an
+/// immediate answer instead of waiting for every request to time out
+pub struct CircuitBreaker {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+// Consecutive failed requests that open the circuit, and how long it stays open
+const FAILURE_THRESHOLD: u32 = 5;
+const OPEN_DURATION: Duration = Duration::from_secs(60);
+
+pub static CIRCUIT_BREAKER: Lazy<Mutex<CircuitBreaker>> = Lazy::new(|| {
+    Mutex::new(CircuitBreaker {
+        failures: 0,
+        open_until: None,
+    })
+});
+
+impl CircuitBreaker {
+    /// Whether a request may be sent now; once the delay is over, requests are tried again
+    pub fn allows_request(&self) -> bool {
+        match self.open_until {
+            Some(until) => Instant::now() >= until,
+            None => true,
+        }
+    }
+
+    pub fn record_success(&mut self) {
+        self.failures = 0;
+        self.open_until = None;
+    }
+
+    pub fn record_failure(&mut self) {
+        self.failures += 1;
+        if self.failures >= FAILURE_THRESHOLD {
+            warn!(
+                "{} failed requests in a row, pausing LLM calls for {:?}",
+                self.failures, OPEN_DURATION
+            );
+            self.open_until = Some(Instant::now() + OPEN_DURATION);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_stream_deltas() {
+        let line = r#"data: {"id":"c1","choices":[{"delta":{"content":"Hel"}}]}"#;
+        assert_eq!(
+            parse_sse_line(line),
+            Some(SseEvent::Delta {
+                id: Some(String::from("c1")),
+                content: String::from("Hel"),
+                usage: None,
+            })
+        );
+    }
+
+    #[test]
+    fn reads_the_usage_of_the_last_chunk() {
+        let openai =
+            r#"data: {"id":"c1","choices":[],"usage":{"prompt_tokens":12,"completion_tokens":34}}"#;
+        let groq = r#"data: {"id":"c1","choices":[{"delta":{}}],"x_groq":{"usage":{"prompt_tokens":1,"completion_tokens":2}}}"#;
+        let usage = |line| match parse_sse_line(line) {
+            Some(SseEvent::Delta { usage, content, .. }) if content.is_empty() => usage,
+            other => panic!("unexpected event {:?}", other),
+        };
+        assert_eq!(
+            usage(openai),
+            Some(Usage {
+                prompt_tokens: 12,
+                completion_tokens: 34,
+            })
+        );
+        assert_eq!(
+            usage(groq),
+            Some(Usage {
+                prompt_tokens: 1,
+                completion_tokens: 2,
+            })
+        );
+    }
+
+    #[test]
+    fn honors_retry_after_within_the_limit() {
+        let minute = Some(Duration::from_secs(60));
+        assert_eq!(retry_delay(minute, 0), None);
+        let longest = Some(MAX_RETRY_AFTER);
+        assert_eq!(retry_delay(longest, 0), longest);
+        let long_backoff = Some(MAX_BACKOFF + Duration::from_secs(5));
+        assert_eq!(retry_delay(long_backoff, 3), long_backoff);
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_a_cap() {
+        let first = retry_delay(None, 0).unwrap();
+        assert!(first >= BASE_BACKOFF && first <= BASE_BACKOFF * 2);
+        let third = retry_delay(None, 2).unwrap();
+        assert!(third >= BASE_BACKOFF * 4 && third <= BASE_BACKOFF * 5);
+        assert_eq!(retry_delay(None, 40), Some(MAX_BACKOFF));
+    }
+
+    #[test]
+    fn ends_on_done_and_skips_other_lines() {
+        assert_eq!(parse_sse_line("data: [DONE]"), Some(SseEvent::Done));
+        assert_eq!(parse_sse_line(""), None);
+        assert_eq!(parse_sse_line(": keep-alive"), None);
+        assert_eq!(parse_sse_line("event: message"), None);
+        assert_eq!(parse_sse_line("data: {not json"), None);
+    }
+}
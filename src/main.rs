@@ -1,29 +1,69 @@
 use chrono::Local;
+use once_cell::sync::Lazy;
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::fs;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, Instant};
 use teloxide::{
-    dispatching::dialogue::InMemStorage, prelude::*, types::ParseMode, utils::command::BotCommands,
+    dispatching::dialogue::InMemStorage,
+    net::Download,
+    prelude::*,
+    types::{Chat, ChatAction, InlineKeyboardMarkup, MessageId, ParseMode, User},
+    utils::command::BotCommands,
+    ApiError,
 };
+use tokio::sync::{Mutex, OwnedMutexGuard};
 
 type QuizDialogue = Dialogue<Option<QuizManager>, InMemStorage<Option<QuizManager>>>;
 
-use crate::types::{FlashCardData, QuizManager};
+use crate::llm::StreamEvent;
+use crate::prompts::Template;
+use crate::scheduling::Rating;
+use crate::types::{FlashCardData, NumericSpec, QuizManager, QuizMode, QuizState, TimeLimit};
 
 extern crate pretty_env_logger;
 #[macro_use]
 extern crate log;
 
+pub mod cache;
 pub mod constants;
+pub mod conversation;
 pub mod db;
+pub mod grading;
+pub mod keyboards;
+pub mod limits;
+pub mod llm;
 pub mod parsers;
+pub mod practice;
+pub mod prompts;
+pub mod render;
+pub mod reports;
 pub mod requests;
+pub mod scheduling;
+pub mod selection;
+pub mod summarize;
 pub mod types;
+pub mod units;
+pub mod usage;
 pub mod utils;
 
 #[derive(Deserialize)]
 struct Config {
     tg_key: String,
-    llm_key: String,
+    /// Not needed by local servers and the mock provider
+    llm_key: Option<String>,
+    #[serde(default)]
+    llm: llm::LlmConfig,
+    /// File overriding the built-in prompts, e.g. prompts.it.toml
+    prompts: Option<String>,
+    /// Telegram ids of the users allowed to see the usage of everyone and to set tiers
+    #[serde(default)]
+    admins: Vec<u64>,
+    #[serde(default)]
+    limits: limits::LimitsConfig,
 }
 
 #[derive(BotCommands, Clone)]
@@ -31,7 +71,6 @@ struct Config {
     rename_rule = "lowercase",
     description = "These commands are supported:"
 )]
-
 enum Command {
     #[command(description = "ℹ️ Display this help menu.")]
     Help,
@@ -45,9 +84,20 @@ enum Command {
         topic: String,
         difficulty: u64,
     },
+    #[command(
+        description = "🔢 Create a flashcard with a numeric answer, a tolerance and an optional unit",
+        parse_with = parsers::parse_numeric_flashcard
+    )]
+    Numeric {
+        question: String,
+        answer: String,
+        spec: NumericSpec,
+        topic: String,
+        difficulty: u64,
+    },
     #[command(description = "🧑‍💻 Register yourself to track flashcards & review sessions 📚")]
     Register,
-    #[command(description = "💡 Explain a concept in simple terms.")]
+    #[command(description = "💡 Explain a concept in simple terms (option: --level advanced).")]
     Explain(String),
     #[command(description = "📖 Define a concept clearly.")]
     Define(String),
@@ -55,27 +105,129 @@ enum Command {
     Translate { language: String, text: String },
     #[command(description = "⚖️ Compare two concepts side by side 🔍", parse_with = parsers::parse_two_delimited_strings)]
     Compare { concept1: String, concept2: String },
-    #[command(description = "📰 Summarize the given text into key points.")]
+    #[command(
+        description = "📰 Summarize a text, or the message or text file you reply to. Options: --format tldr|bullets|outline|study-notes"
+    )]
     Summarize(String),
     #[command(description = "📂 List all flashcards available for a topic.")]
     List(String),
-    #[command(description = "🎯 Start a quiz using flashcards for a chosen topic.")]
+    #[command(
+        description = "🎯 Start a quiz on a topic, several (bio,chem) or all (options: --time 20s, --order adaptive, --n 15, --shuffle, --seed 42)."
+    )]
     Quiz(String),
+    #[command(
+        description = "🏃 Answer as many flashcards of a topic as you can in a fixed time, e.g. /sprint bio 5m."
+    )]
+    Sprint(String),
+    #[command(
+        description = "🎓 Simulate an exam: n random cards, harder ones more likely, graded at the end."
+    )]
+    Exam(String),
+    #[command(description = "🧠 Review a topic by revealing each answer and rating your recall.")]
+    Recall(String),
+    #[command(
+        description = "⚡ Warm up on a topic by judging whether each card is shown with its right answer."
+    )]
+    TrueFalse(String),
+    #[command(
+        description = "📝 Take a generated practice test on any subject, no flashcards needed, e.g. /practice photosynthesis 5."
+    )]
+    Practice(String),
+    #[command(
+        description = "🔍 Get a hint for the current quiz question (costs part of its credit)."
+    )]
+    Hint,
+    #[command(description = "⏭️ Skip the current quiz question.")]
+    Skip,
+    #[command(description = "📋 Replay a finished quiz answer by answer.")]
+    Report(String),
     #[command(description = "🛑 Exit an ongoing quiz.")]
     Stop,
+    #[command(description = "▶️ Resume a stopped quiz (the latest one if no id is given).")]
+    Resume(String),
+    #[command(description = "🗂️ Show your recent quizzes, completed and stopped.")]
+    History,
+    #[command(
+        description = "🧹 Forget the conversations in this chat (reply to an answer to ask a follow-up question)."
+    )]
+    Forget,
+    #[command(
+        description = "📊 Show your AI usage of the last 30 days (admins: /usage users or /usage models)."
+    )]
+    Usage(String),
+    #[command(
+        description = "🏷️ Admins only: move a user to a tier of limits, e.g. /settier 123456 pro."
+    )]
+    SetTier(String),
 }
 
+#[derive(Clone, Copy)]
 pub enum Actions {
     Summarize,
     Explain,
     Define,
     Translate,
     Compare,
+    Hint,
+    Why,
+    Practice,
+    FollowUp,
+}
+
+impl Actions {
+    /// Name of the action in the `[llm.actions]` section of config.toml
+    pub fn key(self) -> &'static str {
+        match self {
+            Actions::Summarize => "summarize",
+            Actions::Explain => "explain",
+            Actions::Define => "define",
+            Actions::Translate => "translate",
+            Actions::Compare => "compare",
+            Actions::Hint => "hint",
+            Actions::Why => "why",
+            Actions::Practice => "practice",
+            Actions::FollowUp => "followup",
+        }
+    }
+
+    /// Whether the same input always deserves the same answer, which can then be cached.
+    /// Creative actions and follow-ups are always answered anew.
+    pub fn is_cacheable(self) -> bool {
+        match self {
+            Actions::Summarize
+            | Actions::Explain
+            | Actions::Define
+            | Actions::Translate
+            | Actions::Compare => true,
+            Actions::Hint | Actions::Why | Actions::Practice | Actions::FollowUp => false,
+        }
+    }
+
+    /// The prompt of the action, its template filled with `variables`
+    pub fn prompt(self, variables: &[(&str, &str)]) -> String {
+        let template = match self {
+            Actions::Summarize => Template::Summarize,
+            Actions::Explain => Template::Explain,
+            Actions::Define => Template::Define,
+            Actions::Translate => Template::Translate,
+            Actions::Compare => Template::Compare,
+            Actions::Hint => Template::Hint,
+            Actions::Why => Template::Why,
+            Actions::Practice => Template::Practice,
+            Actions::FollowUp => Template::FollowUp,
+        };
+        prompts::render(template, variables)
+    }
 }
 
-fn load_config() -> Config {
+// Read once at startup, changes to config.toml need a restart
+static CONFIG: Lazy<Config> = Lazy::new(|| {
     let config_text = fs::read_to_string("config.toml").expect("Failed to read config.toml");
     toml::from_str(&config_text).expect("Failed to parse config.toml")
+});
+
+fn load_config() -> &'static Config {
+    &CONFIG
 }
 
 #[tokio::main]
@@ -84,19 +236,30 @@ async fn main() {
     info!("Starting your favourite study bot...");
 
     let config = load_config();
+    prompts::init(config.prompts.as_deref())
+        .unwrap_or_else(|e| panic!("Failed to load the prompts: {}", e));
 
-    let bot = Bot::new(config.tg_key);
+    let bot = Bot::new(&config.tg_key);
 
     Dispatcher::builder(
         bot,
-        Update::filter_message()
-            .branch(dptree::entry().filter_command::<Command>()
-                .enter_dialogue::<Message, InMemStorage<Option<QuizManager>>, Option<QuizManager>>()
-                .endpoint(answer))
+        dptree::entry()
             .branch(
                 Update::filter_message()
-                    .enter_dialogue::<Message, InMemStorage<Option<QuizManager>>, Option<QuizManager>>()
-                    .endpoint(quiz_handler),
+                    .branch(dptree::entry().filter_command::<Command>()
+                        .enter_dialogue::<Message, InMemStorage<Option<QuizManager>>, Option<QuizManager>>()
+                        .endpoint(answer))
+                    .branch(
+                        Update::filter_message()
+                            .enter_dialogue::<Message, InMemStorage<Option<QuizManager>>, Option<QuizManager>>()
+                            .branch(dptree::filter_map(follow_up_thread).endpoint(follow_up))
+                            .endpoint(quiz_handler),
+                    ),
+            )
+            .branch(
+                Update::filter_callback_query()
+                    .enter_dialogue::<CallbackQuery, InMemStorage<Option<QuizManager>>, Option<QuizManager>>()
+                    .endpoint(callback_handler),
             ),
     )
     .dependencies(dptree::deps![InMemStorage::<Option<QuizManager>>::new()]) //creates in memory storage (with empty QuizManager)
@@ -113,15 +276,13 @@ async fn answer(
 ) -> ResponseResult<()> {
     match cmd {
         Command::Help => {
-            bot.send_message(msg.chat.id, Command::descriptions().to_string())
-                .await?
+            send_chunked(&bot, msg.chat.id, Command::descriptions().to_string()).await?
         }
         Command::Register => {
             let u = match msg.from {
                 Some(u) => u,
                 None => {
-                    bot.send_message(msg.chat.id, "Error while processing your request")
-                        .await?;
+                    send_chunked(&bot, msg.chat.id, "Error while processing your request").await?;
                     return Ok(());
                 }
             };
@@ -157,161 +318,149 @@ async fn answer(
                     );
                 }
             }
-            bot.send_message(
-                msg.chat.id,
-                format!("You successfully registered on quiz pal"),
-            )
-            .await?
-        }
-        Command::Summarize(text) => {
-            info!("{}", text);
-            let result: Result<requests::ModelAnswer, requests::RequestError> =
-                requests::request(text.as_str(), Actions::Summarize).await;
-
-            match result {
-                Ok(v) => {
-                    bot.send_message(msg.chat.id, utils::escape_md_v2(&v.content).as_str())
-                        .parse_mode(ParseMode::MarkdownV2)
-                        .await?
-                }
-                Err(error) => match error {
-                    requests::RequestError::Http(e) => {
-                        bot.send_message(
-                            msg.chat.id,
-                            format!("Error while processign your request {}", e),
-                        )
-                        .await?
-                    }
-                    requests::RequestError::Extract(e) => {
-                        bot.send_message(
-                            msg.chat.id,
-                            format!("Error while processign your request {}", e),
-                        )
-                        .await?
-                    }
-                },
-            }
+            send_chunked(&bot, msg.chat.id, "You successfully registered on quiz pal").await?
         }
-        Command::Explain(text) => {
-            let result: Result<requests::ModelAnswer, requests::RequestError> =
-                requests::request(text.as_str(), Actions::Explain).await;
-
-            match result {
-                Ok(v) => {
-                    bot.send_message(msg.chat.id, utils::escape_md_v2(&v.content).as_str())
-                        .parse_mode(ParseMode::MarkdownV2)
-                        .await?
+        Command::Summarize(args) => {
+            let (args, fresh) = parsers::take_no_cache(&args);
+            let (format, text) = match parsers::parse_summarize_args(&args) {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    send_chunked(&bot, msg.chat.id, e).await?;
+                    return Ok(());
                 }
-                Err(error) => match error {
-                    requests::RequestError::Http(e) => {
-                        bot.send_message(
+            };
+            let text = match summary_source(&bot, &msg, text).await? {
+                Ok(text) => text,
+                Err(e) => {
+                    send_chunked(&bot, msg.chat.id, e).await?;
+                    return Ok(());
+                }
+            };
+            if !admit(&bot, &msg.chat, msg.from.as_ref()).await? {
+                return Ok(());
+            }
+
+            let parts = summarize::chunk_count(&text);
+            let text = if parts > 1 {
+                send_chunked(
+                    &bot,
+                    msg.chat.id,
+                    format!("📚 Long text, summarizing it in {} parts...", parts),
+                )
+                .await?;
+                bot.send_chat_action(msg.chat.id, ChatAction::Typing)
+                    .await?;
+                match summarize::condense(&text, msg.from.as_ref().map(|u| u.id.0)).await {
+                    Ok(text) => text,
+                    Err(requests::RequestError::Unavailable) => {
+                        send_chunked(
+                            &bot,
                             msg.chat.id,
-                            format!("Error while processign your request {}", e),
+                            "⚠️ The AI is temporarily unavailable, please try again in a minute.",
                         )
-                        .await?
+                        .await?;
+                        return Ok(());
+                    }
+                    Err(requests::RequestError::Limited(e)) => {
+                        send_chunked(&bot, msg.chat.id, e.to_string()).await?;
+                        return Ok(());
                     }
-                    requests::RequestError::Extract(e) => {
-                        bot.send_message(
+                    Err(e) => {
+                        send_chunked(
+                            &bot,
                             msg.chat.id,
                             format!("Error while processign your request {}", e),
                         )
-                        .await?
+                        .await?;
+                        return Ok(());
                     }
-                },
+                }
+            } else {
+                text
+            };
+
+            reply_streaming(
+                &bot,
+                &msg,
+                conversation::Thread(msg.id.0.into()),
+                &text,
+                summarize::final_prompt(&text, format),
+                Actions::Summarize,
+                fresh,
+            )
+            .await?;
+            return Ok(());
+        }
+        Command::Explain(args) => {
+            if !admit(&bot, &msg.chat, msg.from.as_ref()).await? {
+                return Ok(());
             }
+            let (args, fresh) = parsers::take_no_cache(&args);
+            let (level, concept) = parsers::parse_explain_args(&args);
+            reply_streaming(
+                &bot,
+                &msg,
+                conversation::Thread(msg.id.0.into()),
+                &concept,
+                Actions::Explain.prompt(&[("concept", &concept), ("level", &level)]),
+                Actions::Explain,
+                fresh,
+            )
+            .await?;
+            return Ok(());
         }
         Command::Define(text) => {
-            let result: Result<requests::ModelAnswer, requests::RequestError> =
-                requests::request(text.as_str(), Actions::Define).await;
-
-            match result {
-                Ok(v) => {
-                    bot.send_message(msg.chat.id, utils::escape_md_v2(&v.content).as_str())
-                        .parse_mode(ParseMode::MarkdownV2)
-                        .await?
-                }
-                Err(error) => match error {
-                    requests::RequestError::Http(e) => {
-                        bot.send_message(
-                            msg.chat.id,
-                            format!("Error while processign your request {}", e),
-                        )
-                        .await?
-                    }
-                    requests::RequestError::Extract(e) => {
-                        bot.send_message(
-                            msg.chat.id,
-                            format!(
-                                "Error while processign yourconvert char to &str rust request {}",
-                                e
-                            ),
-                        )
-                        .await?
-                    }
-                },
+            if !admit(&bot, &msg.chat, msg.from.as_ref()).await? {
+                return Ok(());
             }
+            let (text, fresh) = parsers::take_no_cache(&text);
+            reply_streaming(
+                &bot,
+                &msg,
+                conversation::Thread(msg.id.0.into()),
+                &text,
+                Actions::Define.prompt(&[("concept", &text)]),
+                Actions::Define,
+                fresh,
+            )
+            .await?;
+            return Ok(());
         }
         Command::Translate { language, text } => {
-            let result: Result<requests::ModelAnswer, requests::RequestError> = requests::request(
-                format!("Translate text in {}\nText: {}\n", language, text).as_str(),
+            if !admit(&bot, &msg.chat, msg.from.as_ref()).await? {
+                return Ok(());
+            }
+            let (language, fresh_language) = parsers::take_no_cache(&language);
+            let (text, fresh_text) = parsers::take_no_cache(&text);
+            reply_streaming(
+                &bot,
+                &msg,
+                conversation::Thread(msg.id.0.into()),
+                &text,
+                Actions::Translate.prompt(&[("language", &language), ("text", &text)]),
                 Actions::Translate,
+                fresh_language || fresh_text,
             )
-            .await;
-
-            match result {
-                Ok(v) => {
-                    bot.send_message(msg.chat.id, utils::escape_md_v2(&v.content).as_str())
-                        .parse_mode(ParseMode::MarkdownV2)
-                        .await?
-                }
-                Err(error) => match error {
-                    requests::RequestError::Http(e) => {
-                        bot.send_message(
-                            msg.chat.id,
-                            format!("Error while processign your request {}", e),
-                        )
-                        .await?
-                    }
-                    requests::RequestError::Extract(e) => {
-                        bot.send_message(
-                            msg.chat.id,
-                            format!("Error while processign your request {}", e),
-                        )
-                        .await?
-                    }
-                },
-            }
+            .await?;
+            return Ok(());
         }
         Command::Compare { concept1, concept2 } => {
-            let result: Result<requests::ModelAnswer, requests::RequestError> = requests::request(
-                format!("First concept {}\nSecond concept: {}\n", concept1, concept2).as_str(),
+            if !admit(&bot, &msg.chat, msg.from.as_ref()).await? {
+                return Ok(());
+            }
+            let (concept1, fresh1) = parsers::take_no_cache(&concept1);
+            let (concept2, fresh2) = parsers::take_no_cache(&concept2);
+            reply_streaming(
+                &bot,
+                &msg,
+                conversation::Thread(msg.id.0.into()),
+                &format!("{} | {}", concept1, concept2),
+                Actions::Compare.prompt(&[("concept1", &concept1), ("concept2", &concept2)]),
                 Actions::Compare,
+                fresh1 || fresh2,
             )
-            .await;
-
-            match result {
-                Ok(v) => {
-                    bot.send_message(msg.chat.id, utils::escape_md_v2(&v.content).as_str())
-                        .parse_mode(ParseMode::MarkdownV2)
-                        .await?
-                }
-                Err(error) => match error {
-                    requests::RequestError::Http(e) => {
-                        bot.send_message(
-                            msg.chat.id,
-                            format!("Error while processign your request {}", e),
-                        )
-                        .await?
-                    }
-                    requests::RequestError::Extract(e) => {
-                        bot.send_message(
-                            msg.chat.id,
-                            format!("Error while processign your request {}", e),
-                        )
-                        .await?
-                    }
-                },
-            }
+            .await?;
+            return Ok(());
         }
         Command::FlashCard {
             question,
@@ -322,31 +471,24 @@ async fn answer(
             let u = match msg.from {
                 Some(u) => u,
                 None => {
-                    bot.send_message(
+                    send_chunked(
+                        &bot,
                         msg.chat.id,
-                        format!("User ID not available, couldn't create flashcard"),
+                        "User ID not available, couldn't create flashcard",
                     )
                     .await?;
                     return Ok(());
                 }
             };
 
-            let user_exist = {
-                let db = db::get_db();
-
-                let query = "SELECT id FROM users WHERE id = ?";
-                let mut user_stmt = db.prepare(query).unwrap();
-                user_stmt.bind((1, u.id.0.to_string().as_str())).unwrap();
-
-                user_stmt.next().is_ok()
-            };
-
-            if !user_exist {
-                bot.send_message(
+            if !db::user_exists(u.id.0) {
+                send_chunked(
+                    &bot,
                     msg.chat.id,
-                    format!("You need to register in order to crate a flashcard"),
+                    "You need to register in order to crate a flashcard",
                 )
                 .await?;
+                return Ok(());
             }
 
             let res: Result<(), sqlite::Error> = {
@@ -371,20 +513,92 @@ async fn answer(
             };
 
             if res.is_ok() {
-                bot.send_message(msg.chat.id, format!("You successfully created a flashcard"))
-                    .await?
+                send_chunked(&bot, msg.chat.id, "You successfully created a flashcard").await?
             } else {
-                bot.send_message(msg.chat.id, format!("Error while creating flashcard",))
-                    .await?
+                send_chunked(&bot, msg.chat.id, "Error while creating flashcard").await?
+            }
+        }
+        Command::Numeric {
+            question,
+            answer,
+            spec,
+            topic,
+            difficulty,
+        } => {
+            let u = match msg.from {
+                Some(u) => u,
+                None => {
+                    send_chunked(
+                        &bot,
+                        msg.chat.id,
+                        "User ID not available, couldn't create flashcard",
+                    )
+                    .await?;
+                    return Ok(());
+                }
+            };
+
+            if !db::user_exists(u.id.0) {
+                send_chunked(
+                    &bot,
+                    msg.chat.id,
+                    "You need to register in order to crate a flashcard",
+                )
+                .await?;
+                return Ok(());
+            }
+
+            let res: Result<(), sqlite::Error> = {
+                let db = db::get_db();
+                let mut statement = db
+                    .prepare(
+                        "
+                        INSERT INTO flashcards (
+                            user_id, question, answer, topic, difficulty,
+                            card_type, numeric_value, tolerance, tolerance_kind, unit
+                        ) VALUES (?,?,?,?,?,'numeric',?,?,?,?)",
+                    )
+                    .unwrap();
+
+                let (tolerance, tolerance_kind) = match spec.tolerance {
+                    types::Tolerance::Relative(r) => (r, "relative"),
+                    types::Tolerance::Absolute(a) => (a, "absolute"),
+                };
+
+                statement.bind((1, u.id.to_string().as_str())).unwrap();
+                statement.bind((2, question.as_str())).unwrap();
+                statement.bind((3, answer.as_str())).unwrap();
+                statement.bind((4, topic.as_str())).unwrap();
+                statement
+                    .bind((5, difficulty.to_string().as_str()))
+                    .unwrap();
+                statement.bind((6, spec.value)).unwrap();
+                statement.bind((7, tolerance)).unwrap();
+                statement.bind((8, tolerance_kind)).unwrap();
+                statement.bind((9, spec.unit.as_deref())).unwrap();
+
+                statement.next().map(|_| ())
+            };
+
+            if res.is_ok() {
+                send_chunked(
+                    &bot,
+                    msg.chat.id,
+                    "You successfully created a numeric flashcard",
+                )
+                .await?
+            } else {
+                send_chunked(&bot, msg.chat.id, "Error while creating flashcard").await?
             }
         }
         Command::List(topic) => {
             let u = match msg.from {
                 Some(u) => u,
                 None => {
-                    bot.send_message(
+                    send_chunked(
+                        &bot,
                         msg.chat.id,
-                        format!("User ID not available, couldn't create flashcard"),
+                        "User ID not available, couldn't create flashcard",
                     )
                     .await?;
                     return Ok(());
@@ -394,14 +608,15 @@ async fn answer(
             let cards: Vec<FlashCardData> = {
                 let db = db::get_db();
                 let mut statement = db
-                    .prepare(
+                    .prepare(format!(
                         "
-                        SELECT question, answer, difficulty
+                        SELECT {}
                         FROM flashcards
                         WHERE topic = ? AND user_id = ?
                         ORDER BY difficulty
                     ",
-                    )
+                        FlashCardData::COLUMNS
+                    ))
                     .unwrap();
 
                 statement.bind((1, topic.as_str())).unwrap();
@@ -409,127 +624,1525 @@ async fn answer(
 
                 let mut rows = Vec::new();
                 while let Ok(sqlite::State::Row) = statement.next() {
-                    let question = statement.read::<String, _>("question").unwrap();
-                    let answer = statement.read::<String, _>("answer").unwrap();
-                    let difficulty = statement.read::<i64, _>("difficulty").unwrap();
+                    let card = FlashCardData::read(&statement).unwrap();
 
-                    debug!("question = {}", question);
-                    debug!("answer = {}", answer);
-                    debug!("difficulty = {}", difficulty);
+                    debug!("question = {}", card.question);
+                    debug!("answer = {}", card.answer);
+                    debug!("difficulty = {}", card.difficulty);
 
-                    rows.push(FlashCardData {
-                        question,
-                        answer,
-                        difficulty,
-                    });
+                    rows.push(card);
                 }
                 rows
             };
 
             let mut message: String = String::from("");
 
-            if cards.len() == 0 {
-                bot.send_message(
+            if cards.is_empty() {
+                send_chunked(
+                    &bot,
                     msg.chat.id,
                     format!("No flashcards found for topic {}", topic),
                 )
                 .await?;
+                return Ok(());
             }
 
             for (i, card) in cards.iter().enumerate() {
+                let answer = match &card.kind {
+                    types::CardKind::Numeric(spec) => {
+                        format!("{} ({})", card.answer, spec.tolerance)
+                    }
+                    types::CardKind::Text | types::CardKind::Choice(_) => card.answer.clone(),
+                };
                 message.push_str(
                     format!(
                         "Flashcard {}\nQuestion:\n{}\nAnswer:\n{}\nDifficulty (1-10): {}\n\n",
                         i + 1,
                         card.question,
-                        card.answer,
+                        answer,
                         card.difficulty
                     )
                     .as_str(),
                 )
             }
-            bot.send_message(msg.chat.id, message).await?
+            send_chunked(&bot, msg.chat.id, message).await?
         }
-        Command::Quiz(topic) => {
-            let u = match msg.from {
-                Some(ref u) => u,
-                None => {
-                    bot.send_message(msg.chat.id, "Error while processing your request")
-                        .await?;
+        Command::Quiz(args) => {
+            match parsers::parse_quiz_args(&args) {
+                Ok(quiz_info) => start_quiz(&bot, &msg, &dialogue, quiz_info).await?,
+                Err(e) => {
+                    send_chunked(&bot, msg.chat.id, e).await?;
+                }
+            }
+            return Ok(());
+        }
+        Command::Sprint(args) => {
+            match parsers::parse_sprint_args(&args) {
+                Ok(quiz_info) => start_quiz(&bot, &msg, &dialogue, quiz_info).await?,
+                Err(e) => {
+                    send_chunked(&bot, msg.chat.id, e).await?;
+                }
+            }
+            return Ok(());
+        }
+        Command::Exam(args) => {
+            match parsers::parse_exam_args(&args) {
+                Ok(quiz_info) => start_quiz(&bot, &msg, &dialogue, quiz_info).await?,
+                Err(e) => {
+                    send_chunked(&bot, msg.chat.id, e).await?;
+                }
+            }
+            return Ok(());
+        }
+        Command::TrueFalse(args) => {
+            match parsers::parse_quiz_args(&args) {
+                Ok(quiz_info) => {
+                    let quiz_info = types::QuizData {
+                        mode: QuizMode::TrueFalse,
+                        ..quiz_info
+                    };
+                    start_quiz(&bot, &msg, &dialogue, quiz_info).await?
+                }
+                Err(e) => {
+                    send_chunked(&bot, msg.chat.id, e).await?;
+                }
+            }
+            return Ok(());
+        }
+        Command::Practice(args) => {
+            let quiz_info = match parsers::parse_practice_args(&args) {
+                Ok(quiz_info) => quiz_info,
+                Err(e) => {
+                    send_chunked(&bot, msg.chat.id, e).await?;
                     return Ok(());
                 }
             };
-
-            let user_exist = {
-                let db = db::get_db();
-                let query = "SELECT id FROM users WHERE id = ?";
-                let mut user_stmt = db.prepare(query).unwrap();
-                user_stmt.bind((1, u.id.0.to_string().as_str())).unwrap();
-                user_stmt.next().is_ok()
+            let user_id = match quiz_starter(&bot, &msg, &dialogue).await? {
+                Some(id) => id,
+                None => return Ok(()),
             };
-
-            if !user_exist {
-                bot.send_message(msg.chat.id, "You need to register in order to do a quiz")
-                    .await?;
+            if !admit(&bot, &msg.chat, msg.from.as_ref()).await? {
                 return Ok(());
             }
 
-            let state = dialogue.get().await.unwrap();
+            send_chunked(
+                &bot,
+                msg.chat.id,
+                format!("⏳ Preparing a practice test on {}...", quiz_info.topic),
+            )
+            .await?;
 
-            if state.is_some() && state.unwrap().is_some() {
-                bot.send_message(
-                    msg.chat.id,
-                    "Please finish your quiz before starting a new one",
-                )
-                .await?;
-                return Ok(());
-            }
+            let count = quiz_info
+                .question_count
+                .unwrap_or(constants::PRACTICE_DEFAULT_QUESTIONS)
+                .to_string();
+            let result = requests::request_structured::<practice::GeneratedTest>(
+                &Actions::Practice.prompt(&[("subject", &quiz_info.topic), ("count", &count)]),
+                Actions::Practice,
+                Some(user_id),
+                &practice::GeneratedTest::schema(),
+            )
+            .await;
 
-            let quiz_info = types::QuizData {
-                topic,
-                user_id: u.id.0,
+            let cards = match result {
+                Ok(test) => match test.into_cards(&quiz_info.topic) {
+                    Ok(cards) => cards,
+                    Err(e) => {
+                        warn!("unusable practice test for {}: {}", quiz_info.topic, e);
+                        send_chunked(
+                            &bot,
+                            msg.chat.id,
+                            "Could not prepare the practice test, please try again.",
+                        )
+                        .await?;
+                        return Ok(());
+                    }
+                },
+                Err(requests::RequestError::Extract(e)) => {
+                    warn!("unreadable practice test for {}: {}", quiz_info.topic, e);
+                    send_chunked(
+                        &bot,
+                        msg.chat.id,
+                        "Could not prepare the practice test, please try again.",
+                    )
+                    .await?;
+                    return Ok(());
+                }
+                Err(e) => {
+                    send_chunked(
+                        &bot,
+                        msg.chat.id,
+                        format!("Error while processign your request {}", e),
+                    )
+                    .await?;
+                    return Ok(());
+                }
             };
 
-            let new_quiz_manager = types::QuizManager::new(quiz_info);
-
-            if dialogue.update(new_quiz_manager).await.is_err() {
-                bot.send_message(msg.chat.id, "An error occurred while starting the quiz")
-                    .await?;
-                return Ok(());
+            let quiz_info = types::QuizData {
+                user_id,
+                ..quiz_info
+            };
+            launch_quiz(
+                &bot,
+                msg.chat.id,
+                &dialogue,
+                QuizManager::from_cards(quiz_info, cards),
+            )
+            .await?;
+            return Ok(());
+        }
+        Command::Recall(args) => {
+            match parsers::parse_quiz_args(&args) {
+                Ok(quiz_info) => {
+                    let quiz_info = types::QuizData {
+                        mode: QuizMode::Recall,
+                        ..quiz_info
+                    };
+                    start_quiz(&bot, &msg, &dialogue, quiz_info).await?
+                }
+                Err(e) => {
+                    send_chunked(&bot, msg.chat.id, e).await?;
+                }
             }
-
-            // Success message
-            bot.send_message(msg.chat.id, "When you are ready, enter any text to start!")
-                .await?
+            return Ok(());
         }
-        Command::Stop => {
+        Command::Hint => {
+            let quiz_manager = match dialogue.get().await.unwrap() {
+                Some(Some(m)) => m,
+                _ => {
+                    send_chunked(&bot, msg.chat.id, "Hints are only available during a quiz.")
+                        .await?;
+                    return Ok(());
+                }
+            };
+
+            let level = match quiz_manager.next_hint() {
+                Some(level) => level,
+                None => {
+                    send_chunked(&bot, msg.chat.id, "No more hints available right now.").await?;
+                    return Ok(());
+                }
+            };
+            let position = quiz_manager.get_position();
+            let card = quiz_manager.current_card().unwrap().clone();
+
+            let hint = match level {
+                1 => utils::first_letter_hint(&card.answer),
+                2 => utils::letter_count_hint(&card.answer),
+                _ => {
+                    if !admit(&bot, &msg.chat, msg.from.as_ref()).await? {
+                        return Ok(());
+                    }
+                    let result = requests::request(
+                        &Actions::Hint
+                            .prompt(&[("question", &card.question), ("answer", &card.answer)]),
+                        Actions::Hint,
+                        msg.from.as_ref().map(|u| u.id.0),
+                    )
+                    .await;
+                    match result {
+                        Ok(v) => v.content,
+                        Err(e) => {
+                            send_chunked(
+                                &bot,
+                                msg.chat.id,
+                                format!("Error while processign your request {}", e),
+                            )
+                            .await?;
+                            return Ok(());
+                        }
+                    }
+                }
+            };
+
+            let text = format!("💡 Hint {}/{}: {}", level, types::MAX_HINTS, hint);
+            if level < types::MAX_HINTS {
+                send_chunked(&bot, msg.chat.id, text).await?;
+            } else {
+                send_rendered(&bot, msg.chat.id, &text).await?;
+            }
+
+            // the hint is only charged once delivered, on the question it was asked for
+            let _quiz = lock_quiz(msg.chat.id).await;
+            if let Ok(Some(Some(mut quiz_manager))) = dialogue.get().await {
+                if quiz_manager.use_hint(position, level) {
+                    dialogue.update(quiz_manager).await.unwrap();
+                }
+            }
+            return Ok(());
+        }
+        Command::Skip => {
+            let _quiz = lock_quiz(msg.chat.id).await;
+            let mut quiz_manager = match dialogue.get().await.unwrap() {
+                Some(Some(m)) => m,
+                _ => {
+                    send_chunked(
+                        &bot,
+                        msg.chat.id,
+                        "You can only skip questions during a quiz.",
+                    )
+                    .await?;
+                    return Ok(());
+                }
+            };
+
+            let is_exam = quiz_manager.get_mode() == QuizMode::Exam;
+            let skipped = match quiz_manager.skip() {
+                Some(card) => card.answer.clone(),
+                None => {
+                    send_chunked(&bot, msg.chat.id, "There is no question to skip yet.").await?;
+                    return Ok(());
+                }
+            };
+
+            if is_exam {
+                send_chunked(&bot, msg.chat.id, "⏭️ Question skipped.").await?;
+            } else {
+                send_chunked(
+                    &bot,
+                    msg.chat.id,
+                    format!("⏭️ Question skipped, the answer was: {}", skipped),
+                )
+                .await?;
+            }
+            advance_quiz(&bot, msg.chat.id, &dialogue, quiz_manager).await?;
+            return Ok(());
+        }
+        Command::Report(quiz_id) => {
+            let u = match msg.from {
+                Some(ref u) => u,
+                None => {
+                    send_chunked(&bot, msg.chat.id, "Error while processing your request").await?;
+                    return Ok(());
+                }
+            };
+
+            let quiz_id = match quiz_id.trim().trim_start_matches('#').parse::<i64>() {
+                Ok(id) => id,
+                Err(_) => {
+                    send_chunked(
+                        &bot,
+                        msg.chat.id,
+                        "Please, provide a quiz id: /report <quiz_id>",
+                    )
+                    .await?;
+                    return Ok(());
+                }
+            };
+
+            match reports::load_report(quiz_id, u.id.0) {
+                Ok(Some(report)) => send_chunked(&bot, msg.chat.id, report.render()).await?,
+                Ok(None) => {
+                    send_chunked(
+                        &bot,
+                        msg.chat.id,
+                        format!("No quiz found with id {}", quiz_id),
+                    )
+                    .await?
+                }
+                Err(_) => {
+                    send_chunked(&bot, msg.chat.id, "Error while loading the quiz report").await?
+                }
+            }
+        }
+        Command::Stop => {
+            let _quiz = lock_quiz(msg.chat.id).await;
             let state = dialogue.get().await.unwrap();
 
-            if let Some(Some(mut manager)) = state {
-                if manager.save_quiz_result().is_err() {
-                    bot.send_message(msg.chat.id, "Error while saving quiz results to DB")
+            let manager = if let Some(Some(mut manager)) = state {
+                if manager.save_quiz_result(QuizState::Stopped).is_err() {
+                    send_chunked(&bot, msg.chat.id, "Error while saving quiz results to DB")
                         .await?;
                     return Ok(());
                 }
+                manager
             } else {
-                bot.send_message(
+                send_chunked(
+                    &bot,
                     msg.chat.id,
                     "You cannot stop a quiz if you didn't even start one.",
                 )
                 .await?;
                 return Ok(());
-            }
+            };
 
             if dialogue.update(None).await.is_err() {
-                bot.send_message(msg.chat.id, "An error occurred while ending the quiz.")
+                send_chunked(
+                    &bot,
+                    msg.chat.id,
+                    "An error occurred while ending the quiz.",
+                )
+                .await?;
+                return Ok(());
+            }
+
+            if manager.is_practice() {
+                send_chunked_with(
+                    &bot,
+                    msg.chat.id,
+                    "Practice test stopped.",
+                    save_missed_offer(&manager),
+                )
+                .await?;
+                return Ok(());
+            }
+
+            match manager.get_quiz_id() {
+                Some(id) => {
+                    send_chunked(
+                        &bot,
+                        msg.chat.id,
+                        format!(
+                            "Quiz stopped correctly. Type /resume {} to continue it later.",
+                            id
+                        ),
+                    )
+                    .await?
+                }
+                None => send_chunked(&bot, msg.chat.id, "Quiz stopped correctly.").await?,
+            }
+        }
+        Command::Resume(quiz_id) => {
+            let u = match msg.from {
+                Some(ref u) => u,
+                None => {
+                    send_chunked(&bot, msg.chat.id, "Error while processing your request").await?;
+                    return Ok(());
+                }
+            };
+
+            let quiz_id = match quiz_id.trim().trim_start_matches('#') {
+                "" => None,
+                id => match id.parse::<i64>() {
+                    Ok(id) => Some(id),
+                    Err(_) => {
+                        send_chunked(
+                            &bot,
+                            msg.chat.id,
+                            "Please, provide a quiz id: /resume [quiz_id]",
+                        )
+                        .await?;
+                        return Ok(());
+                    }
+                },
+            };
+
+            if let Some(Some(_)) = dialogue.get().await.unwrap() {
+                send_chunked(
+                    &bot,
+                    msg.chat.id,
+                    "Please finish your quiz before resuming another one",
+                )
+                .await?;
+                return Ok(());
+            }
+
+            let quiz_manager = match types::QuizManager::resume(u.id.0, quiz_id) {
+                Ok(Some(m)) => m,
+                Ok(None) => {
+                    send_chunked(
+                        &bot,
+                        msg.chat.id,
+                        "No stopped quiz to resume. Type /history to see your quizzes.",
+                    )
                     .await?;
+                    return Ok(());
+                }
+                Err(_) => {
+                    send_chunked(&bot, msg.chat.id, "Error while loading the quiz").await?;
+                    return Ok(());
+                }
+            };
+
+            send_chunked(
+                &bot,
+                msg.chat.id,
+                format!(
+                    "▶️ Resuming your quiz on {}, {} questions answered so far.",
+                    quiz_manager.get_topic(),
+                    quiz_manager.get_answered() + quiz_manager.get_skipped()
+                ),
+            )
+            .await?;
+            advance_quiz(&bot, msg.chat.id, &dialogue, quiz_manager).await?;
+            return Ok(());
+        }
+        Command::History => {
+            let u = match msg.from {
+                Some(ref u) => u,
+                None => {
+                    send_chunked(&bot, msg.chat.id, "Error while processing your request").await?;
+                    return Ok(());
+                }
+            };
+
+            match reports::load_history(u.id.0, 20) {
+                Ok(quizzes) if quizzes.is_empty() => {
+                    send_chunked(&bot, msg.chat.id, "You have not taken any quiz yet.").await?
+                }
+                Ok(quizzes) => {
+                    send_chunked(
+                        &bot,
+                        msg.chat.id,
+                        quizzes
+                            .iter()
+                            .map(|q| q.render())
+                            .collect::<Vec<_>>()
+                            .join("\n"),
+                    )
+                    .await?
+                }
+                Err(_) => {
+                    send_chunked(&bot, msg.chat.id, "Error while loading your quiz history").await?
+                }
+            }
+        }
+        Command::Usage(args) => {
+            let u = match msg.from {
+                Some(ref u) => u,
+                None => {
+                    send_chunked(&bot, msg.chat.id, "Error while processing your request").await?;
+                    return Ok(());
+                }
+            };
+
+            let config = load_config();
+            let (breakdown, title) = match args.trim() {
+                "" => (
+                    usage::Breakdown::Action(u.id.0),
+                    "📊 Your AI usage by action",
+                ),
+                "users" | "models" if !config.admins.contains(&u.id.0) => {
+                    send_chunked(
+                        &bot,
+                        msg.chat.id,
+                        "Only admins can see the usage of everyone.",
+                    )
+                    .await?;
+                    return Ok(());
+                }
+                "users" => (usage::Breakdown::User, "📊 AI usage by user"),
+                "models" => (usage::Breakdown::Model, "📊 AI usage by model"),
+                _ => {
+                    send_chunked(&bot, msg.chat.id, "Usage: /usage [users|models]").await?;
+                    return Ok(());
+                }
+            };
+
+            match usage::load_usage(breakdown, constants::USAGE_REPORT_DAYS, &config.llm.prices) {
+                Ok(lines) => {
+                    send_chunked(
+                        &bot,
+                        msg.chat.id,
+                        usage::render_usage(
+                            &format!(
+                                "{} in the last {} days:",
+                                title,
+                                constants::USAGE_REPORT_DAYS
+                            ),
+                            &lines,
+                        ),
+                    )
+                    .await?
+                }
+                Err(e) => {
+                    warn!("failed to load the usage: {}", e);
+                    send_chunked(&bot, msg.chat.id, "Error while loading the usage").await?
+                }
+            }
+        }
+        Command::SetTier(args) => {
+            let config = load_config();
+            if !msg
+                .from
+                .as_ref()
+                .is_some_and(|u| config.admins.contains(&u.id.0))
+            {
+                send_chunked(&bot, msg.chat.id, "Only admins can set tiers.").await?;
                 return Ok(());
             }
 
-            bot.send_message(msg.chat.id, format!("Quiz stopped correctly."))
+            let (user_id, tier) = match parsers::parse_tier_args(&args) {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    send_chunked(&bot, msg.chat.id, e).await?;
+                    return Ok(());
+                }
+            };
+            if !config.limits.is_tier(&tier) {
+                send_chunked(
+                    &bot,
+                    msg.chat.id,
+                    format!("Unknown tier {}, add it to [limits.tiers] first", tier),
+                )
+                .await?;
+                return Ok(());
+            }
+
+            match limits::set_user_tier(user_id, &tier) {
+                Ok(true) => {
+                    send_chunked(
+                        &bot,
+                        msg.chat.id,
+                        format!("User {} is now in the {} tier.", user_id, tier),
+                    )
+                    .await?
+                }
+                Ok(false) => {
+                    send_chunked(
+                        &bot,
+                        msg.chat.id,
+                        format!("User {} is not registered.", user_id),
+                    )
+                    .await?
+                }
+                Err(_) => {
+                    send_chunked(&bot, msg.chat.id, "Error while processing your request").await?
+                }
+            }
+        }
+        Command::Forget => match conversation::forget(msg.chat.id.0) {
+            Ok(0) => send_chunked(&bot, msg.chat.id, "There is no conversation to forget.").await?,
+            Ok(_) => {
+                send_chunked(
+                    &bot,
+                    msg.chat.id,
+                    "🧹 Done, follow-up questions now start from scratch.",
+                )
                 .await?
+            }
+            Err(_) => {
+                send_chunked(&bot, msg.chat.id, "Error while processing your request").await?
+            }
+        },
+    };
+    Ok(())
+}
+
+/// Text for `/summarize`: the one given with the command, or else the message it replies to,
+/// either its text or a text file attached to it
+async fn summary_source(
+    bot: &Bot,
+    msg: &Message,
+    text: String,
+) -> ResponseResult<Result<String, String>> {
+    if !text.is_empty() {
+        return Ok(Ok(text));
+    }
+    let Some(reply) = msg.reply_to_message() else {
+        return Ok(Err(String::from(
+            "Please, provide a text or reply to a message: /summarize [--format <name>] <text>",
+        )));
+    };
+
+    if let Some(document) = reply.document() {
+        let is_text = document
+            .mime_type
+            .as_ref()
+            .is_some_and(|m| m.essence_str().starts_with("text/"))
+            || document
+                .file_name
+                .as_ref()
+                .is_some_and(|n| n.ends_with(".txt") || n.ends_with(".md"));
+        if !is_text {
+            return Ok(Err(String::from(
+                "Only text documents (.txt, .md) can be summarized",
+            )));
+        }
+        if document.file.size > constants::SUMMARY_MAX_DOCUMENT_SIZE {
+            return Ok(Err(format!(
+                "This document is too large, the limit is {} KB",
+                constants::SUMMARY_MAX_DOCUMENT_SIZE / 1024
+            )));
+        }
+
+        let file = bot.get_file(document.file.id.clone()).await?;
+        let mut content = Vec::new();
+        if let Err(e) = bot.download_file(&file.path, &mut content).await {
+            warn!("failed to download document {}: {}", file.path, e);
+            return Ok(Err(String::from("Could not download this document")));
+        }
+        return Ok(match String::from_utf8(content) {
+            Ok(content) if !content.trim().is_empty() => Ok(content),
+            Ok(_) => Err(String::from("This document is empty")),
+            Err(_) => Err(String::from("This document is not valid UTF-8 text")),
+        });
+    }
+
+    match reply.text().or(reply.caption()) {
+        Some(text) if !text.trim().is_empty() => Ok(Ok(text.to_string())),
+        _ => Ok(Err(String::from(
+            "The message you replied to has no text to summarize",
+        ))),
+    }
+}
+
+/// Answers with the model, editing a placeholder message as the answer is generated.
+/// The conversation keeps `question`, the user's own words, rather than the whole `prompt`.
+async fn reply_streaming(
+    bot: &Bot,
+    msg: &Message,
+    thread: conversation::Thread,
+    question: &str,
+    prompt: String,
+    action: Actions,
+    fresh: bool,
+) -> ResponseResult<()> {
+    let chat_id = msg.chat.id;
+    let user_id = msg.from.as_ref().map(|u| u.id.0);
+    let placeholder = send_chunked(bot, chat_id, "⏳").await?;
+    bot.send_chat_action(chat_id, ChatAction::Typing).await?;
+
+    let history = conversation::history(chat_id.0, thread).unwrap_or_else(|e| {
+        warn!("failed to load conversation in chat {}: {}", chat_id, e);
+        Vec::new()
+    });
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let request = tokio::spawn(async move {
+        requests::request_streaming(&history, &prompt, action, user_id, fresh, tx).await
+    });
+
+    let mut content = String::new();
+    let mut shown = String::new();
+    let mut last_edit = Instant::now();
+    loop {
+        // the typing action fades after a few seconds, so it is renewed until the first token
+        let delta = match tokio::time::timeout(constants::TYPING_INTERVAL, rx.recv()).await {
+            Ok(Some(StreamEvent::Delta(delta))) => delta,
+            Ok(Some(StreamEvent::Restart)) => {
+                content.clear();
+                if !shown.is_empty() {
+                    shown.clear();
+                    if let Err(e) = bot.edit_message_text(chat_id, placeholder.id, "⏳").await {
+                        warn!("failed to reset streamed answer in chat {}: {}", chat_id, e);
+                    }
+                }
+                continue;
+            }
+            Ok(None) => break,
+            Err(_) => {
+                if content.is_empty() {
+                    bot.send_chat_action(chat_id, ChatAction::Typing).await?;
+                }
+                continue;
+            }
+        };
+        content.push_str(&delta);
+
+        if last_edit.elapsed() >= constants::STREAM_EDIT_INTERVAL && content.trim() != shown {
+            // Telegram counts the length in UTF-16 code units
+            let mut length = 0;
+            shown = content
+                .trim()
+                .chars()
+                .take_while(|c| {
+                    length += c.len_utf16();
+                    length <= constants::MAX_MESSAGE_LENGTH
+                })
+                .collect();
+            // a failed intermediate edit is not worth stopping for, the final one matters
+            if let Err(e) = bot
+                .edit_message_text(chat_id, placeholder.id, shown.as_str())
+                .await
+            {
+                warn!(
+                    "failed to update streamed answer in chat {}: {}",
+                    chat_id, e
+                );
+            }
+            last_edit = Instant::now();
+        }
+    }
+
+    let result = match request.await {
+        Ok(result) => result,
+        Err(e) => {
+            warn!("streaming request in chat {} failed: {}", chat_id, e);
+            bot.edit_message_text(
+                chat_id,
+                placeholder.id,
+                "Error while processign your request",
+            )
+            .await?;
+            return Ok(());
+        }
+    };
+    match result {
+        Ok(v) => {
+            let message_ids: Vec<i64> = edit_rendered(bot, chat_id, placeholder.id, &v.content)
+                .await?
+                .into_iter()
+                .map(|id| id.0.into())
+                .collect();
+            if let Err(e) =
+                conversation::record(chat_id.0, thread, question, &v.content, &message_ids)
+            {
+                warn!("failed to save conversation in chat {}: {}", chat_id, e);
+            }
+        }
+        Err(requests::RequestError::Unavailable) => {
+            bot.edit_message_text(
+                chat_id,
+                placeholder.id,
+                "⚠️ The AI is temporarily unavailable, please try again in a minute.",
+            )
+            .await?;
+        }
+        Err(requests::RequestError::Limited(e)) => {
+            bot.edit_message_text(chat_id, placeholder.id, e.to_string())
+                .await?;
+        }
+        Err(e) => {
+            bot.edit_message_text(
+                chat_id,
+                placeholder.id,
+                format!("Error while processign your request {}", e),
+            )
+            .await?;
+        }
+    };
+    Ok(())
+}
+
+/// Checks the rate limits and the daily quota of a user before a request to the model,
+/// telling them why when the request cannot be sent
+async fn admit(bot: &Bot, chat: &Chat, user: Option<&User>) -> ResponseResult<bool> {
+    // only channel posts come without a sender, and channels are run by their admins
+    let Some(user) = user else {
+        return Ok(true);
+    };
+    let group_id = (chat.is_group() || chat.is_supergroup()).then_some(chat.id.0);
+
+    match limits::admit(&load_config().limits, user.id.0, group_id) {
+        Ok(()) => Ok(true),
+        Err(e) => {
+            send_chunked(bot, chat.id, e.to_string()).await?;
+            Ok(false)
+        }
+    }
+}
+
+// The conversation continued by a message, when it replies to an answer of the bot outside
+// of a quiz, where a reply is an answer to the question
+fn follow_up_thread(msg: Message, quiz: Option<QuizManager>) -> Option<conversation::Thread> {
+    if quiz.is_some() {
+        return None;
+    }
+    let reply = msg.reply_to_message()?;
+    match conversation::reply_thread(msg.chat.id.0, reply.id.0.into()) {
+        Ok(thread) => thread,
+        Err(e) => {
+            warn!(
+                "failed to look up conversation in chat {}: {}",
+                msg.chat.id, e
+            );
+            None
+        }
+    }
+}
+
+/// Answers a reply to an answer of the bot, with the earlier messages of its conversation
+async fn follow_up(bot: Bot, msg: Message, thread: conversation::Thread) -> ResponseResult<()> {
+    if !admit(&bot, &msg.chat, msg.from.as_ref()).await? {
+        return Ok(());
+    }
+    match msg.text() {
+        Some(text) => {
+            reply_streaming(
+                &bot,
+                &msg,
+                thread,
+                text,
+                Actions::FollowUp.prompt(&[("text", text)]),
+                Actions::FollowUp,
+                false,
+            )
+            .await
+        }
+        None => {
+            send_chunked(
+                &bot,
+                msg.chat.id,
+                "Please, reply with a text message to continue the conversation.",
+            )
+            .await?;
+            Ok(())
+        }
+    }
+}
+
+// Telegram refuses the whole message when its formatting is invalid
+fn is_formatting_error(error: &teloxide::RequestError) -> bool {
+    matches!(
+        error,
+        teloxide::RequestError::Api(ApiError::CantParseEntities(_))
+    )
+}
+
+/// Sends a message, split in numbered parts when it is longer than Telegram allows
+async fn send_chunked(
+    bot: &Bot,
+    chat_id: ChatId,
+    text: impl Into<String>,
+) -> ResponseResult<Message> {
+    send_chunked_with(bot, chat_id, text, None).await
+}
+
+/// Like `send_chunked`, with the keyboard attached to the last part
+async fn send_chunked_with(
+    bot: &Bot,
+    chat_id: ChatId,
+    text: impl Into<String>,
+    keyboard: Option<InlineKeyboardMarkup>,
+) -> ResponseResult<Message> {
+    let mut parts = render::split_message(&text.into(), constants::MAX_MESSAGE_LENGTH, false);
+    let last = parts.pop().unwrap_or_default();
+    for part in parts {
+        bot.send_message(chat_id, part).await?;
+    }
+
+    let request = bot.send_message(chat_id, last);
+    match keyboard {
+        Some(keyboard) => request.reply_markup(keyboard).await,
+        None => request.await,
+    }
+}
+
+// The parts of a model answer rendered as Telegram HTML, or a notice if it is empty
+fn rendered_parts(markdown: &str) -> Vec<String> {
+    let html = render::markdown_to_html(markdown);
+    if html.is_empty() {
+        return vec![String::from("⚠️ The model returned an empty answer.")];
+    }
+    render::split_message(&html, constants::MAX_MESSAGE_LENGTH, true)
+}
+
+// Sends a part of a rendered answer, or the same part as plain text if Telegram rejects it
+async fn send_html_part(bot: &Bot, chat_id: ChatId, part: &str) -> ResponseResult<Message> {
+    match bot
+        .send_message(chat_id, part)
+        .parse_mode(ParseMode::Html)
+        .await
+    {
+        Err(e) if is_formatting_error(&e) => {
+            warn!("falling back to plain text in chat {}: {}", chat_id, e);
+            bot.send_message(chat_id, render::html_to_plain(part)).await
+        }
+        result => result,
+    }
+}
+
+/// Sends a model answer with its Markdown rendered, every part Telegram rejects being sent
+/// as plain text instead. Returns the ids of the messages sent.
+async fn send_rendered(
+    bot: &Bot,
+    chat_id: ChatId,
+    markdown: &str,
+) -> ResponseResult<Vec<MessageId>> {
+    let mut sent = Vec::new();
+    for part in &rendered_parts(markdown) {
+        sent.push(send_html_part(bot, chat_id, part).await?.id);
+    }
+    Ok(sent)
+}
+
+/// Replaces the text of a message with a model answer, rendered like `send_rendered`.
+/// Parts beyond the first are sent as new messages. Returns the ids of every part.
+async fn edit_rendered(
+    bot: &Bot,
+    chat_id: ChatId,
+    message_id: MessageId,
+    markdown: &str,
+) -> ResponseResult<Vec<MessageId>> {
+    // an empty answer replaces the placeholder too, rather than leaving it waiting
+    let parts = rendered_parts(markdown);
+
+    match bot
+        .edit_message_text(chat_id, message_id, parts[0].as_str())
+        .parse_mode(ParseMode::Html)
+        .await
+    {
+        Err(e) if is_formatting_error(&e) => {
+            warn!("falling back to plain text in chat {}: {}", chat_id, e);
+            bot.edit_message_text(chat_id, message_id, render::html_to_plain(&parts[0]))
+                .await?;
+        }
+        result => {
+            result?;
+        }
+    }
+
+    let mut sent = vec![message_id];
+    for part in &parts[1..] {
+        sent.push(send_html_part(bot, chat_id, part).await?.id);
+    }
+    Ok(sent)
+}
+
+/// Checks that the sender is registered and not in a quiz already, returning their id
+async fn quiz_starter(
+    bot: &Bot,
+    msg: &Message,
+    dialogue: &QuizDialogue,
+) -> ResponseResult<Option<u64>> {
+    let u = match msg.from {
+        Some(ref u) => u,
+        None => {
+            send_chunked(bot, msg.chat.id, "Error while processing your request").await?;
+            return Ok(None);
+        }
+    };
+
+    if !db::user_exists(u.id.0) {
+        send_chunked(
+            bot,
+            msg.chat.id,
+            "You need to register in order to do a quiz",
+        )
+        .await?;
+        return Ok(None);
+    }
+
+    let state = dialogue.get().await.unwrap();
+
+    if state.is_some() && state.unwrap().is_some() {
+        send_chunked(
+            bot,
+            msg.chat.id,
+            "Please finish your quiz before starting a new one",
+        )
+        .await?;
+        return Ok(None);
+    }
+
+    Ok(Some(u.id.0))
+}
+
+async fn start_quiz(
+    bot: &Bot,
+    msg: &Message,
+    dialogue: &QuizDialogue,
+    mut quiz_info: types::QuizData,
+) -> ResponseResult<()> {
+    quiz_info.user_id = match quiz_starter(bot, msg, dialogue).await? {
+        Some(id) => id,
+        None => return Ok(()),
+    };
+
+    launch_quiz(bot, msg.chat.id, dialogue, QuizManager::new(quiz_info)).await
+}
+
+/// Introduces a new quiz and stores it in the dialogue
+async fn launch_quiz(
+    bot: &Bot,
+    chat_id: ChatId,
+    dialogue: &QuizDialogue,
+    mut new_quiz_manager: QuizManager,
+) -> ResponseResult<()> {
+    let mode = new_quiz_manager.get_mode();
+
+    match mode {
+        QuizMode::Typed | QuizMode::Exam => {
+            if mode == QuizMode::Exam {
+                if new_quiz_manager.get_total() == 0 {
+                    send_chunked(bot, chat_id, "No flashcards found for this topic").await?;
+                    return Ok(());
+                }
+                send_chunked(bot,
+                    chat_id,
+                    format!(
+                        "🎓 Exam of {} questions. You will only see your results at the end, and hints are disabled.",
+                        new_quiz_manager.get_total()
+                    ),
+                )
+                .await?;
+            }
+
+            if dialogue.update(new_quiz_manager).await.is_err() {
+                send_chunked(bot, chat_id, "An error occurred while starting the quiz").await?;
+                return Ok(());
+            }
+
+            // Success message
+            send_chunked(bot, chat_id, "When you are ready, enter any text to start!").await?;
+        }
+        QuizMode::Recall | QuizMode::TrueFalse => {
+            new_quiz_manager.is_new();
+            if new_quiz_manager.get_total() == 0 {
+                send_chunked(bot, chat_id, "No flashcards found for this topic").await?;
+                return Ok(());
+            }
+
+            let intro = match mode {
+                QuizMode::Recall => {
+                    "🧠 Try to recall each answer, then reveal it and rate how well you remembered."
+                }
+                _ => {
+                    "⚡ Is the answer shown under each question the right one? Press True or False."
+                }
+            };
+            send_chunked(bot, chat_id, intro).await?;
+            advance_quiz(bot, chat_id, dialogue, new_quiz_manager).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Button offering to keep the questions missed in a practice test, if there are any
+fn save_missed_offer(quiz_manager: &QuizManager) -> Option<InlineKeyboardMarkup> {
+    let quiz_id = quiz_manager
+        .get_quiz_id()
+        .filter(|_| quiz_manager.is_practice())?;
+    // a right answer that took hints scores less than a point, but was not missed
+    match practice::missed_count(quiz_id) {
+        Ok(0) => None,
+        Ok(_) => Some(keyboards::save_missed(quiz_id)),
+        Err(e) => {
+            warn!(
+                "failed to count the missed questions of quiz {}: {}",
+                quiz_id, e
+            );
+            None
         }
+    }
+}
+
+// One lock per chat with a quiz, dropped once nobody holds or waits for it
+static QUIZ_LOCKS: Lazy<StdMutex<HashMap<ChatId, Arc<Mutex<()>>>>> =
+    Lazy::new(|| StdMutex::new(HashMap::new()));
+
+/// Waits until the quiz of the chat is not being changed elsewhere. Messages, buttons and
+/// timers change a quiz one at a time, so that an answer arriving at the deadline is not
+/// also timed out.
+async fn lock_quiz(chat_id: ChatId) -> OwnedMutexGuard<()> {
+    let lock = {
+        let mut locks = QUIZ_LOCKS.lock().unwrap();
+        locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+        locks.entry(chat_id).or_default().clone()
     };
+    lock.lock_owned().await
+}
+
+/// Sends the next question of the quiz, or its results when no question is left
+async fn advance_quiz(
+    bot: &Bot,
+    chat_id: ChatId,
+    dialogue: &QuizDialogue,
+    mut quiz_manager: QuizManager,
+) -> ResponseResult<()> {
+    let mode = quiz_manager.get_mode();
+    let sprint = quiz_manager.start_clock();
+
+    match quiz_manager.get_question() {
+        Ok(Some(card)) => {
+            let card = card.clone();
+            let asked_at = quiz_manager.asked_at();
+            let time_note = match quiz_manager.get_time_limit() {
+                TimeLimit::PerQuestion(limit) => Some(format!(
+                    "⏱️ You have {} to answer",
+                    utils::format_duration(limit)
+                )),
+                TimeLimit::Sprint(_) => quiz_manager
+                    .sprint_remaining()
+                    .map(|left| format!("⏱️ {} left", utils::format_duration(left))),
+                TimeLimit::None => None,
+            };
+            let time_limit = quiz_manager.get_time_limit();
+            let keyboard = match mode {
+                QuizMode::Recall => Some(keyboards::recall_reveal()),
+                QuizMode::TrueFalse => Some(keyboards::true_false(quiz_manager.get_position())),
+                QuizMode::Typed | QuizMode::Exam => None,
+            };
+            dialogue.update(quiz_manager).await.unwrap();
+            send_question(bot, chat_id, &card, time_note, keyboard).await?;
+
+            if let (TimeLimit::PerQuestion(limit), Some(asked_at)) = (time_limit, asked_at) {
+                tokio::spawn(question_timeout(
+                    bot.clone(),
+                    chat_id,
+                    dialogue.clone(),
+                    limit,
+                    asked_at,
+                ));
+            }
+            if let Some((limit, started_at)) = sprint {
+                tokio::spawn(sprint_timeout(
+                    bot.clone(),
+                    chat_id,
+                    dialogue.clone(),
+                    limit,
+                    started_at,
+                ));
+            }
+        }
+        Ok(None) => {
+            send_chunked_with(
+                bot,
+                chat_id,
+                quiz_summary(&quiz_manager),
+                save_missed_offer(&quiz_manager),
+            )
+            .await?;
+
+            if dialogue.update(None).await.is_err() {
+                send_chunked(bot, chat_id, "An error occurred while ending the quiz.").await?;
+            }
+        }
+        Err(_) => {
+            send_chunked(
+                bot,
+                chat_id,
+                "Your quiz is completed, but an error happened while storing quiz results.",
+            )
+            .await?;
+            dialogue.update(None).await.unwrap();
+        }
+    }
+
+    Ok(())
+}
+
+// Timeout tasks spawned by `advance_quiz` call it back: the boxed future breaks the
+// cycle the compiler would otherwise hit when checking that it is Send
+fn advance_quiz_boxed<'a>(
+    bot: &'a Bot,
+    chat_id: ChatId,
+    dialogue: &'a QuizDialogue,
+    quiz_manager: QuizManager,
+) -> Pin<Box<dyn Future<Output = ResponseResult<()>> + Send + 'a>> {
+    Box::pin(advance_quiz(bot, chat_id, dialogue, quiz_manager))
+}
+
+/// Marks a question as unanswered once its time is over, unless it was answered meanwhile
+async fn question_timeout(
+    bot: Bot,
+    chat_id: ChatId,
+    dialogue: QuizDialogue,
+    limit: Duration,
+    asked_at: Instant,
+) {
+    tokio::time::sleep(limit).await;
+
+    let _quiz = lock_quiz(chat_id).await;
+    let Some((quiz_manager, answer)) = expire_question(&dialogue, asked_at).await else {
+        return;
+    };
+
+    let result = async {
+        send_chunked(
+            &bot,
+            chat_id,
+            format!("⌛ Time is up! The answer was: {}", answer),
+        )
+        .await?;
+        advance_quiz_boxed(&bot, chat_id, &dialogue, quiz_manager).await
+    }
+    .await;
+
+    if let Err(e) = result {
+        warn!("failed to time out question in chat {}: {}", chat_id, e);
+    }
+}
+
+// Times out the question asked at `asked_at`, returning the quiz and the answer of the
+// question, unless the question was answered or skipped meanwhile
+async fn expire_question(
+    dialogue: &QuizDialogue,
+    asked_at: Instant,
+) -> Option<(QuizManager, String)> {
+    let mut quiz_manager = match dialogue.get().await {
+        Ok(Some(Some(m))) if m.asked_at() == Some(asked_at) => m,
+        _ => return None,
+    };
+    let answer = quiz_manager.time_out()?.answer.clone();
+    Some((quiz_manager, answer))
+}
+
+/// Ends a sprint when its time is over, unless the quiz already finished
+async fn sprint_timeout(
+    bot: Bot,
+    chat_id: ChatId,
+    dialogue: QuizDialogue,
+    limit: Duration,
+    started_at: Instant,
+) {
+    tokio::time::sleep(limit).await;
+
+    let _quiz = lock_quiz(chat_id).await;
+    let mut quiz_manager = match dialogue.get().await {
+        Ok(Some(Some(m))) if m.started_at() == Some(started_at) => m,
+        _ => return,
+    };
+    quiz_manager.time_out();
+
+    let summary = match quiz_manager.save_quiz_result(QuizState::Finished) {
+        Ok(_) => format!("🏁 Time is up!\n{}", quiz_summary(&quiz_manager)),
+        Err(_) => String::from("🏁 Time is up! An error happened while storing quiz results."),
+    };
+
+    if dialogue.update(None).await.is_err() {
+        warn!("failed to end sprint in chat {}", chat_id);
+    }
+    if let Err(e) = send_chunked(&bot, chat_id, summary).await {
+        warn!("failed to end sprint in chat {}: {}", chat_id, e);
+    }
+}
+
+async fn send_question(
+    bot: &Bot,
+    chat_id: ChatId,
+    card: &FlashCardData,
+    time_note: Option<String>,
+    keyboard: Option<InlineKeyboardMarkup>,
+) -> ResponseResult<()> {
+    let mut text = format!(
+        "\nNext question:\n{}\n\nDifficulty: {}",
+        card.question, card.difficulty
+    );
+    if let Some(note) = time_note {
+        text.push_str(&format!("\n{}", note));
+    }
+
+    send_chunked_with(bot, chat_id, text, keyboard).await?;
+    Ok(())
+}
+
+fn quiz_summary(quiz_manager: &QuizManager) -> String {
+    let mut summary = match quiz_manager.get_mode() {
+        QuizMode::Typed => format!(
+            "Your quiz is completed, you answered correctly {}/{} questions.",
+            quiz_manager.get_score(),
+            quiz_manager.get_answered()
+        ),
+        QuizMode::Recall => {
+            let ratings = quiz_manager.get_ratings();
+            format!(
+                "Your recall session is completed, your score is {}/{}.\n{}",
+                quiz_manager.get_score(),
+                quiz_manager.get_answered(),
+                Rating::ALL
+                    .iter()
+                    .map(|r| format!("{}: {}", r.label(), ratings[*r as usize]))
+                    .collect::<Vec<_>>()
+                    .join(" · ")
+            )
+        }
+        QuizMode::TrueFalse => format!(
+            "Your true or false warm-up is over, you judged correctly {}/{} statements.",
+            quiz_manager.get_score(),
+            quiz_manager.get_answered()
+        ),
+        QuizMode::Exam => return exam_summary(quiz_manager),
+    };
+
+    if quiz_manager.get_skipped() > 0 {
+        summary.push_str(&format!(
+            "\nSkipped questions: {}",
+            quiz_manager.get_skipped()
+        ));
+    }
+    if quiz_manager.get_timed_out() > 0 {
+        summary.push_str(&format!(
+            "\nQuestions out of time: {}",
+            quiz_manager.get_timed_out()
+        ));
+    }
+    if quiz_manager.get_time_limit() != TimeLimit::None {
+        if let Some(average) = quiz_manager.average_response_time() {
+            summary.push_str(&format!(
+                "\nAverage response time: {:.1}s",
+                average.as_secs_f64()
+            ));
+        }
+    }
+    if let Some(quiz_id) = quiz_manager.get_quiz_id() {
+        summary.push_str(&format!(
+            "\nType /report {} to review every answer.",
+            quiz_id
+        ));
+    }
+    summary
+}
+
+/// Grades a finished exam and appends the full transcript of the answers
+fn exam_summary(quiz_manager: &QuizManager) -> String {
+    let total = quiz_manager.get_total().max(1) as f64;
+    let ratio = quiz_manager.get_score() / total;
+    let verdict = if ratio >= constants::EXAM_PASS_THRESHOLD {
+        "✅ Passed"
+    } else {
+        "❌ Not passed"
+    };
+
+    let mut summary = format!(
+        "🎓 Exam completed: {}/{} ({:.0}%) · {} (pass mark {:.0}%)",
+        quiz_manager.get_score(),
+        quiz_manager.get_total(),
+        ratio * 100.0,
+        verdict,
+        constants::EXAM_PASS_THRESHOLD * 100.0
+    );
+
+    let report = quiz_manager
+        .get_quiz_id()
+        .and_then(|id| reports::load_report(id, quiz_manager.get_user_id()).ok())
+        .flatten();
+    if let Some(report) = report {
+        summary.push_str("\n\n");
+        summary.push_str(&report.render());
+    }
+    summary
+}
+
+async fn callback_handler(
+    bot: Bot,
+    q: CallbackQuery,
+    dialogue: QuizDialogue,
+) -> ResponseResult<()> {
+    bot.answer_callback_query(q.id.clone()).await?;
+
+    let (action, message) = match (
+        q.data.as_deref().and_then(keyboards::CallbackAction::parse),
+        q.regular_message(),
+    ) {
+        (Some(action), Some(message)) => (action, message),
+        _ => return Ok(()),
+    };
+
+    if let keyboards::CallbackAction::Why(answer_id) = action {
+        let answer = match reports::load_answer(answer_id, q.from.id.0) {
+            Ok(Some(answer)) => answer,
+            _ => return Ok(()),
+        };
+        if !admit(&bot, &message.chat, Some(&q.from)).await? {
+            return Ok(());
+        }
+
+        bot.edit_message_reply_markup(message.chat.id, message.id)
+            .await?;
+
+        let result = requests::request(
+            &Actions::Why.prompt(&[
+                ("question", &answer.question),
+                ("reference", &answer.reference_answer),
+                ("answer", answer.given_answer.as_deref().unwrap_or_default()),
+            ]),
+            Actions::Why,
+            Some(q.from.id.0),
+        )
+        .await;
+
+        match result {
+            Ok(v) => {
+                send_rendered(&bot, message.chat.id, &v.content).await?;
+            }
+            Err(e) => {
+                send_chunked(
+                    &bot,
+                    message.chat.id,
+                    format!("Error while processign your request {}", e),
+                )
+                .await?;
+            }
+        };
+        return Ok(());
+    }
+
+    if let keyboards::CallbackAction::SaveMissed(quiz_id) = action {
+        bot.edit_message_reply_markup(message.chat.id, message.id)
+            .await?;
+
+        let text = match practice::save_missed(quiz_id, q.from.id.0) {
+            Ok(0) => String::from("There is no new missed question to save."),
+            Ok(n) => format!("💾 Saved {} flashcard(s). Review them with /quiz.", n),
+            Err(e) => {
+                warn!("failed to save missed questions of quiz {}: {}", quiz_id, e);
+                String::from("Error while saving the flashcards")
+            }
+        };
+        send_chunked(&bot, message.chat.id, text).await?;
+        return Ok(());
+    }
+
+    let _quiz = lock_quiz(message.chat.id).await;
+    let mut quiz_manager = match dialogue.get().await.unwrap() {
+        Some(Some(m)) => m,
+        _ => {
+            send_chunked(
+                &bot,
+                message.chat.id,
+                "This quiz is over. Type /quiz to begin a new one.",
+            )
+            .await?;
+            return Ok(());
+        }
+    };
+
+    match action {
+        keyboards::CallbackAction::RecallShow => {
+            if !quiz_manager.reveal() {
+                return Ok(());
+            }
+            let card = quiz_manager.current_card().unwrap();
+            bot.edit_message_text(
+                message.chat.id,
+                message.id,
+                format!("❓ {}\n\n💡 {}", card.question, card.answer),
+            )
+            .reply_markup(keyboards::recall_rating())
+            .await?;
+            dialogue.update(quiz_manager).await.unwrap();
+        }
+        keyboards::CallbackAction::RecallRate(rating) => {
+            if !quiz_manager.rate(rating) {
+                return Ok(());
+            }
+            let card = quiz_manager.current_card().unwrap();
+            bot.edit_message_text(
+                message.chat.id,
+                message.id,
+                format!(
+                    "❓ {}\n\n💡 {}\n\nYou rated: {}",
+                    card.question,
+                    card.answer,
+                    rating.label()
+                ),
+            )
+            .await?;
+
+            advance_quiz(&bot, message.chat.id, &dialogue, quiz_manager).await?;
+        }
+        keyboards::CallbackAction::TrueFalse { position, value } => {
+            if quiz_manager.get_mode() != QuizMode::TrueFalse
+                || quiz_manager.get_position() != position
+                || quiz_manager.current_card().is_none()
+            {
+                return Ok(());
+            }
+            let verdict = if value { "True" } else { "False" };
+            let feedback = if quiz_manager.check_answer(verdict) {
+                format!("✅ Right, it is {}", verdict.to_lowercase())
+            } else {
+                format!(
+                    "❌ No, it is {}",
+                    quiz_manager.current_card().unwrap().answer.to_lowercase()
+                )
+            };
+            let card = quiz_manager.current_card().unwrap();
+            bot.edit_message_text(
+                message.chat.id,
+                message.id,
+                format!("❓ {}\n\n{}", card.question, feedback),
+            )
+            .await?;
+
+            advance_quiz(&bot, message.chat.id, &dialogue, quiz_manager).await?;
+        }
+        keyboards::CallbackAction::Why(_) | keyboards::CallbackAction::SaveMissed(_) => {}
+    }
+
     Ok(())
 }
 
@@ -538,68 +2151,143 @@ async fn quiz_handler(
     dialogue: QuizDialogue,
     msg: Message,
 ) -> Result<(), teloxide::RequestError> {
+    let _quiz = lock_quiz(msg.chat.id).await;
     let state = dialogue.get().await.unwrap();
     if state.is_some() {
         if let Some(Some(mut quiz_manager)) = state {
             let msg_text = msg.text().unwrap();
 
-            if quiz_manager.is_new() {
-                bot.send_message(msg.chat.id, "First question is coming...")
-                    .await?;
-            } else if quiz_manager.check_answer(msg_text) {
-                bot.send_message(msg.chat.id, "✅ Your answer was correct")
-                    .await?;
-            } else {
-                bot.send_message(msg.chat.id, "❌ Wrong answer").await?;
-            }
-
-            let next = quiz_manager.get_question();
-
-            if next.is_err() {
-                bot.send_message(
-                    msg.chat.id,
-                    "Your quiz is completed, but an error happened while storing quiz results.",
-                )
-                .await?;
-            } else {
-                let option_card = next.ok().and_then(|x| x);
-                if option_card.is_none() {
-                    bot.send_message(
+            match quiz_manager.get_mode() {
+                QuizMode::Recall => {
+                    send_chunked(
+                        &bot,
                         msg.chat.id,
-                        format!(
-                            "Your quiz is completed, you answered correctly {}/{} questions.",
-                            quiz_manager.get_score(),
-                            quiz_manager.get_answered()
-                        ),
+                        "Use the buttons to reveal the answer and rate your recall.",
                     )
                     .await?;
-
-                    if dialogue.update(None).await.is_err() {
-                        bot.send_message(msg.chat.id, "An error occurred while ending the quiz.")
-                            .await?;
-                        return Ok(());
-                    }
-                } else {
-                    let card = option_card.unwrap();
-                    bot.send_message(
+                    return Ok(());
+                }
+                QuizMode::TrueFalse => {
+                    send_chunked(
+                        &bot,
                         msg.chat.id,
-                        format!(
-                            "\nNext question:\n{}\n\nDifficulty: {}",
-                            card.question, card.difficulty
-                        ),
+                        "Use the buttons to answer True or False.",
                     )
                     .await?;
+                    return Ok(());
                 }
+                QuizMode::Typed | QuizMode::Exam => {}
             }
-            dialogue.update(quiz_manager).await.unwrap();
-        } else {
-            bot.send_message(msg.chat.id, "No active quiz. Type /quiz to begin.")
+
+            if quiz_manager.is_new() {
+                send_chunked(&bot, msg.chat.id, "First question is coming...").await?;
+            } else if quiz_manager.get_mode() == QuizMode::Exam {
+                // no feedback until the exam is over
+                quiz_manager.check_answer(msg_text);
+            } else if quiz_manager.check_answer(msg_text) {
+                send_chunked(&bot, msg.chat.id, "✅ Your answer was correct").await?;
+            } else {
+                send_chunked_with(
+                    &bot,
+                    msg.chat.id,
+                    format!(
+                        "❌ Wrong answer, the correct answer is: {}",
+                        quiz_manager.current_card().unwrap().answer
+                    ),
+                    quiz_manager.last_answer_id().map(keyboards::why),
+                )
                 .await?;
+            }
+
+            advance_quiz(&bot, msg.chat.id, &dialogue, quiz_manager).await?;
+        } else {
+            send_chunked(&bot, msg.chat.id, "No active quiz. Type /quiz to begin.").await?;
         }
     } else {
-        bot.send_message(msg.chat.id, "No active quiz. Type /quiz to begin.")
-            .await?;
+        send_chunked(&bot, msg.chat.id, "No active quiz. Type /quiz to begin.").await?;
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{CardKind, QuizData};
+
+    fn card(question: &str, answer: &str) -> FlashCardData {
+        FlashCardData {
+            card_id: None,
+            difficulty: 1,
+            answer: answer.to_string(),
+            question: question.to_string(),
+            topic: String::from("race"),
+            kind: CardKind::Text,
+        }
+    }
+
+    #[test]
+    fn replaces_an_empty_answer_with_a_notice() {
+        assert_eq!(
+            rendered_parts(" \n "),
+            ["⚠️ The model returned an empty answer."]
+        );
+        assert_eq!(rendered_parts("**ok**"), ["<b>ok</b>"]);
+    }
+
+    #[tokio::test]
+    async fn an_answer_at_the_deadline_is_not_also_timed_out() {
+        let chat_id = ChatId(-1);
+        let dialogue = QuizDialogue::new(InMemStorage::new(), chat_id);
+        let info = QuizData {
+            user_id: 1,
+            topic: String::from("race"),
+            time_limit: TimeLimit::PerQuestion(Duration::from_secs(20)),
+            ..QuizData::default()
+        };
+        let mut quiz = QuizManager::from_cards(info, vec![card("2+2", "4"), card("3+3", "6")]);
+        quiz.is_new();
+        quiz.get_question().unwrap();
+        let asked_at = quiz.asked_at().unwrap();
+        dialogue.update(quiz).await.unwrap();
+
+        // the answer is being handled when the timer fires
+        let answering = lock_quiz(chat_id).await;
+        let timer = tokio::spawn({
+            let dialogue = dialogue.clone();
+            async move {
+                let _quiz = lock_quiz(chat_id).await;
+                expire_question(&dialogue, asked_at).await.is_some()
+            }
+        });
+        tokio::task::yield_now().await;
+
+        let mut quiz = dialogue.get().await.unwrap().unwrap().unwrap();
+        assert!(quiz.check_answer("4"));
+        quiz.get_question().unwrap();
+        dialogue.update(quiz).await.unwrap();
+        drop(answering);
+
+        assert!(!timer.await.unwrap());
+        let quiz = dialogue.get().await.unwrap().unwrap().unwrap();
+        assert_eq!((quiz.get_answered(), quiz.get_timed_out()), (1, 0));
+        assert_eq!(quiz.get_position(), 2);
+    }
+
+    #[tokio::test]
+    async fn an_unanswered_question_times_out_once() {
+        let chat_id = ChatId(-2);
+        let dialogue = QuizDialogue::new(InMemStorage::new(), chat_id);
+        let mut quiz = QuizManager::from_cards(QuizData::default(), vec![card("2+2", "4")]);
+        quiz.is_new();
+        quiz.get_question().unwrap();
+        let asked_at = quiz.asked_at().unwrap();
+        dialogue.update(quiz).await.unwrap();
+
+        let Some((quiz, answer)) = expire_question(&dialogue, asked_at).await else {
+            panic!("the question did not time out");
+        };
+        assert_eq!(answer, "4");
+        assert_eq!(quiz.get_timed_out(), 1);
+    }
+}
@@ -1,3 +1,9 @@
+use crate::selection::OrderKind;
+use crate::summarize::SummaryFormat;
+use crate::types::{NumericSpec, QuizData, QuizMode, TimeLimit};
+use crate::{constants, grading};
+use std::time::Duration;
+
 pub fn parse_four_delimited_strings(
     input: String,
 ) -> Result<(String, String, String, u64), teloxide::utils::command::ParseError> {
@@ -41,3 +47,374 @@ pub fn parse_two_delimited_strings(
         })
     }
 }
+
+pub fn parse_numeric_flashcard(
+    input: String,
+) -> Result<(String, String, NumericSpec, String, u64), teloxide::utils::command::ParseError> {
+    let parts: Vec<String> = input.split('|').map(|s| s.trim().to_string()).collect();
+
+    if parts.len() != 5 {
+        return Err(teloxide::utils::command::ParseError::TooFewArguments {
+            expected: 5,
+            found: parts.len(),
+            message: "Please, provide correct number of parameters".to_string(),
+        });
+    }
+
+    let incorrect = |e: grading::QuantityError| {
+        teloxide::utils::command::ParseError::IncorrectFormat(Box::new(e))
+    };
+
+    let (value, unit) = grading::parse_reference(&parts[1]).map_err(incorrect)?;
+    let tolerance = grading::parse_tolerance(&parts[2]).map_err(incorrect)?;
+    let difficulty = parts[4]
+        .parse::<u64>()
+        .map_err(|e| teloxide::utils::command::ParseError::IncorrectFormat(Box::new(e)))?;
+
+    Ok((
+        parts[0].clone(),
+        parts[1].clone(),
+        NumericSpec {
+            value,
+            tolerance,
+            unit,
+        },
+        parts[3].clone(),
+        difficulty,
+    ))
+}
+
+/// Parses durations such as "20s", "5m", "1h" or "1m30s", up to `constants::MAX_TIME_LIMIT`;
+/// a bare number is in seconds
+pub fn parse_duration(input: &str) -> Result<Duration, String> {
+    let invalid = || format!("\"{}\" is not a valid duration, use e.g. 20s or 5m", input);
+
+    let mut total = 0u64;
+    let mut number = String::new();
+
+    for c in input.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            _ => return Err(invalid()),
+        };
+        let seconds = number
+            .parse::<u64>()
+            .ok()
+            .and_then(|n| n.checked_mul(unit))
+            .ok_or_else(invalid)?;
+        total = total.checked_add(seconds).ok_or_else(invalid)?;
+        number.clear();
+    }
+    if !number.is_empty() {
+        let seconds = number.parse::<u64>().map_err(|_| invalid())?;
+        total = total.checked_add(seconds).ok_or_else(invalid)?;
+    }
+
+    if total == 0 {
+        return Err(invalid());
+    }
+    let duration = Duration::from_secs(total);
+    if duration > constants::MAX_TIME_LIMIT {
+        return Err(format!(
+            "{} is too long, a time limit is at most {}h",
+            input.trim(),
+            constants::MAX_TIME_LIMIT.as_secs() / 3600
+        ));
+    }
+    Ok(duration)
+}
+
+/// Parses the arguments of `/quiz`: a topic ("all", or several separated by commas)
+/// followed by optional flags (`--time 20s`, `--order adaptive`, `--n 15`, `--shuffle`, `--seed 42`)
+pub fn parse_quiz_args(input: &str) -> Result<QuizData, String> {
+    let mut data = QuizData::default();
+    let mut topic: Vec<&str> = Vec::new();
+    let mut words = input.split_whitespace();
+
+    while let Some(word) = words.next() {
+        match word {
+            "--time" => {
+                let value = words.next().ok_or("--time needs a duration, e.g. 20s")?;
+                data.time_limit = TimeLimit::PerQuestion(parse_duration(value)?);
+            }
+            "--order" => {
+                let value = words
+                    .next()
+                    .ok_or("--order needs a strategy: difficulty or adaptive")?;
+                data.order = OrderKind::from_key(value).ok_or(format!(
+                    "Unknown order {}, use difficulty or adaptive",
+                    value
+                ))?;
+            }
+            "--n" => {
+                let value = words.next().ok_or("--n needs a number of questions")?;
+                match value.parse::<usize>() {
+                    Ok(n) if n > 0 => data.question_count = Some(n),
+                    _ => return Err(format!("{} is not a valid number of questions", value)),
+                }
+            }
+            "--shuffle" => data.shuffle = true,
+            "--seed" => {
+                let value = words.next().ok_or("--seed needs a number, e.g. 42")?;
+                data.seed = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("{} is not a valid seed", value))?,
+                );
+                data.shuffle = true;
+            }
+            flag if flag.starts_with("--") => return Err(format!("Unknown option {}", flag)),
+            word => topic.push(word),
+        }
+    }
+
+    if topic.is_empty() {
+        return Err(String::from("Please, provide a topic"));
+    }
+    data.topic = topic.join(" ");
+    if data.topics().is_some_and(|t| t.is_empty()) {
+        return Err(String::from("Please, provide a topic"));
+    }
+    Ok(data)
+}
+
+/// Parses the arguments of `/sprint`: a topic followed by the total duration
+pub fn parse_sprint_args(input: &str) -> Result<QuizData, String> {
+    let (topic, duration) = input
+        .trim()
+        .rsplit_once(char::is_whitespace)
+        .ok_or("Please, provide a topic and a duration: /sprint <topic> 5m")?;
+
+    Ok(QuizData {
+        topic: topic.trim().to_string(),
+        time_limit: TimeLimit::Sprint(parse_duration(duration)?),
+        ..QuizData::default()
+    })
+}
+
+// Splits "<topic> [n]" into the topic and the optional trailing count
+fn split_topic_and_count(input: &str) -> Result<(&str, Option<usize>), String> {
+    let input = input.trim();
+    match input.rsplit_once(char::is_whitespace) {
+        Some((topic, n)) if n.chars().all(|c| c.is_ascii_digit()) => Ok((
+            topic.trim(),
+            Some(n.parse::<usize>().map_err(|e| e.to_string())?),
+        )),
+        _ => Ok((input, None)),
+    }
+}
+
+/// Parses the arguments of `/exam`: a topic optionally followed by the number of questions
+pub fn parse_exam_args(input: &str) -> Result<QuizData, String> {
+    let (topic, count) = split_topic_and_count(input)?;
+
+    if topic.is_empty() {
+        return Err(String::from("Please, provide a topic: /exam <topic> [n]"));
+    }
+    if count == Some(0) {
+        return Err(String::from("An exam needs at least one question"));
+    }
+
+    Ok(QuizData {
+        topic: topic.to_string(),
+        mode: QuizMode::Exam,
+        question_count: count,
+        ..QuizData::default()
+    })
+}
+
+/// Parses the arguments of `/practice`: a subject optionally followed by the number of questions
+pub fn parse_practice_args(input: &str) -> Result<QuizData, String> {
+    let (subject, count) = split_topic_and_count(input)?;
+
+    if subject.is_empty() {
+        return Err(String::from(
+            "Please, provide a subject: /practice <subject> [n]",
+        ));
+    }
+    let count = count.unwrap_or(constants::PRACTICE_DEFAULT_QUESTIONS);
+    if count == 0 || count > constants::PRACTICE_MAX_QUESTIONS {
+        return Err(format!(
+            "A practice test has between 1 and {} questions",
+            constants::PRACTICE_MAX_QUESTIONS
+        ));
+    }
+
+    Ok(QuizData {
+        topic: subject.to_string(),
+        question_count: Some(count),
+        ..QuizData::default()
+    })
+}
+
+/// Parses the arguments of `/summarize`: an optional `--format <name>` followed by the text,
+/// which may be empty when the command replies to a message
+pub fn parse_summarize_args(input: &str) -> Result<(Option<SummaryFormat>, String), String> {
+    let input = input.trim();
+    let Some(rest) = input.strip_prefix("--format") else {
+        return Ok((None, input.to_string()));
+    };
+
+    let rest = rest.trim_start();
+    let (name, text) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    let format = SummaryFormat::from_key(name).ok_or_else(|| {
+        let names: Vec<&str> = SummaryFormat::ALL.iter().map(|f| f.key()).collect();
+        format!("Unknown format {}, use one of: {}", name, names.join(", "))
+    })?;
+    Ok((Some(format), text.trim().to_string()))
+}
+
+/// Parses the arguments of `/explain`: an optional `--level <level>` followed by the concept
+pub fn parse_explain_args(input: &str) -> (String, String) {
+    let input = input.trim();
+    if let Some(rest) = input.strip_prefix("--level") {
+        let rest = rest.trim_start();
+        if let Some((level, concept)) = rest.split_once(char::is_whitespace) {
+            return (level.to_string(), concept.trim().to_string());
+        }
+    }
+    (
+        constants::EXPLAIN_DEFAULT_LEVEL.to_string(),
+        input.to_string(),
+    )
+}
+
+/// Parses the arguments of `/settier`: a user id followed by the name of a tier
+pub fn parse_tier_args(input: &str) -> Result<(u64, String), String> {
+    let usage = "Usage: /settier <user id> <tier>";
+    let mut words = input.split_whitespace();
+    let (Some(user_id), Some(tier), None) = (words.next(), words.next(), words.next()) else {
+        return Err(String::from(usage));
+    };
+    let user_id = user_id
+        .parse()
+        .map_err(|_| format!("{} is not a valid user id", user_id))?;
+    Ok((user_id, tier.to_lowercase()))
+}
+
+/// Removes the `--no-cache` modifier from the arguments of an AI command, telling whether it
+/// was there. The rest of the text is kept as typed.
+pub fn take_no_cache(input: &str) -> (String, bool) {
+    const FLAG: &str = "--no-cache";
+    let mut start = 0;
+    while let Some(found) = input[start..].find(FLAG) {
+        let at = start + found;
+        let end = at + FLAG.len();
+        let alone = input[..at]
+            .chars()
+            .next_back()
+            .is_none_or(char::is_whitespace)
+            && input[end..].chars().next().is_none_or(char::is_whitespace);
+        if alone {
+            let text = format!("{} {}", input[..at].trim_end(), input[end..].trim_start());
+            return (text.trim().to_string(), true);
+        }
+        start = end;
+    }
+    (input.to_string(), false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("20s"), Ok(Duration::from_secs(20)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("1m30s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration(" 45 "), Ok(Duration::from_secs(45)));
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("0s").is_err());
+        assert!(parse_duration("5d").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("-5s").is_err());
+    }
+
+    #[test]
+    fn rejects_overflowing_and_overlong_durations() {
+        assert!(parse_duration("5000000000000000000h").is_err());
+        assert!(parse_duration("18446744073709551615s1s").is_err());
+        assert!(parse_duration("99999999999999999999").is_err());
+        assert!(parse_duration("25h").is_err());
+        assert_eq!(parse_duration("24h"), Ok(constants::MAX_TIME_LIMIT));
+        assert!(parse_quiz_args("x --time 5000000000000000000h").is_err());
+    }
+
+    #[test]
+    fn parses_quiz_flags() {
+        let Ok(data) = parse_quiz_args("bio, chem --time 20s --order adaptive --n 15 --seed 42")
+        else {
+            panic!("valid arguments were refused");
+        };
+        assert_eq!(data.topic, "bio, chem");
+        assert_eq!(data.topics(), Some(vec!["bio", "chem"]));
+        assert_eq!(
+            data.time_limit,
+            TimeLimit::PerQuestion(Duration::from_secs(20))
+        );
+        assert_eq!(data.order, OrderKind::Adaptive);
+        assert_eq!(data.question_count, Some(15));
+        assert_eq!(data.seed, Some(42));
+        assert!(data.shuffle);
+    }
+
+    #[test]
+    fn defaults_quiz_options() {
+        let Ok(data) = parse_quiz_args("all") else {
+            panic!("valid arguments were refused");
+        };
+        assert_eq!(data.topics(), None);
+        assert_eq!(data.time_limit, TimeLimit::None);
+        assert_eq!(data.order, OrderKind::Difficulty);
+        assert_eq!(data.question_count, None);
+        assert!(!data.shuffle);
+    }
+
+    #[test]
+    fn rejects_invalid_quiz_args() {
+        assert!(parse_quiz_args("").is_err());
+        assert!(parse_quiz_args(",").is_err());
+        assert!(parse_quiz_args("bio --time").is_err());
+        assert!(parse_quiz_args("bio --order random").is_err());
+        assert!(parse_quiz_args("bio --n 0").is_err());
+        assert!(parse_quiz_args("bio --seed x").is_err());
+        assert!(parse_quiz_args("bio --fast").is_err());
+    }
+
+    #[test]
+    fn takes_the_no_cache_flag_anywhere() {
+        assert_eq!(
+            take_no_cache("--no-cache entropy"),
+            (String::from("entropy"), true)
+        );
+        assert_eq!(
+            take_no_cache("the  entropy --no-cache"),
+            (String::from("the  entropy"), true)
+        );
+        assert_eq!(
+            take_no_cache("--level advanced --no-cache photosynthesis"),
+            (String::from("--level advanced photosynthesis"), true)
+        );
+    }
+
+    #[test]
+    fn leaves_the_text_alone_without_the_flag() {
+        assert_eq!(take_no_cache("entropy"), (String::from("entropy"), false));
+        assert_eq!(
+            take_no_cache("a--no-cache b --no-caches"),
+            (String::from("a--no-cache b --no-caches"), false)
+        );
+    }
+}
@@ -0,0 +1,348 @@
+use crate::db;
+use crate::types::{CardKind, FlashCardData};
+
+use serde::Deserialize;
+use sqlite::State;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum PracticeError {
+    #[error("the model returned no usable question")]
+    Empty,
+}
+
+/// A question as generated by the model
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum GeneratedQuestion {
+    ShortAnswer {
+        question: String,
+        answer: String,
+    },
+    MultipleChoice {
+        question: String,
+        choices: Vec<String>,
+        answer: String,
+    },
+    TrueFalse {
+        question: String,
+        answer: bool,
+    },
+}
+
+/// A practice test as generated by the model
+#[derive(Deserialize)]
+pub struct GeneratedTest {
+    questions: Vec<GeneratedQuestion>,
+}
+
+impl GeneratedTest {
+    /// JSON schema of the reply asked to the model
+    pub fn schema() -> serde_json::Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": {
+                "questions": {
+                    "type": "array",
+                    "items": {
+                        "oneOf": [
+                            {
+                                "type": "object",
+                                "properties": {
+                                    "type": { "const": "short_answer" },
+                                    "question": { "type": "string" },
+                                    "answer": { "type": "string" }
+                                },
+                                "required": ["type", "question", "answer"]
+                            },
+                            {
+                                "type": "object",
+                                "properties": {
+                                    "type": { "const": "multiple_choice" },
+                                    "question": { "type": "string" },
+                                    "choices": { "type": "array", "items": { "type": "string" } },
+                                    "answer": {
+                                        "type": "string",
+                                        "description": "the correct choice, copied exactly"
+                                    }
+                                },
+                                "required": ["type", "question", "choices", "answer"]
+                            },
+                            {
+                                "type": "object",
+                                "properties": {
+                                    "type": { "const": "true_false" },
+                                    "question": { "type": "string", "description": "a statement" },
+                                    "answer": { "type": "boolean" }
+                                },
+                                "required": ["type", "question", "answer"]
+                            }
+                        ]
+                    }
+                }
+            },
+            "required": ["questions"]
+        })
+    }
+
+    /// Turns the test into unsaved cards, dropping malformed questions
+    pub fn into_cards(self, subject: &str) -> Result<Vec<FlashCardData>, PracticeError> {
+        let cards: Vec<FlashCardData> = self
+            .questions
+            .into_iter()
+            .filter_map(|q| q.into_card(subject))
+            .collect();
+
+        if cards.is_empty() {
+            return Err(PracticeError::Empty);
+        }
+        Ok(cards)
+    }
+}
+
+// Choices of a multiple choice question, one per letter from A to Z
+const MAX_CHOICES: usize = 26;
+
+impl GeneratedQuestion {
+    fn into_card(self, subject: &str) -> Option<FlashCardData> {
+        let (question, answer, kind) = match self {
+            GeneratedQuestion::ShortAnswer { question, answer } => {
+                (question, answer.trim().to_string(), CardKind::Text)
+            }
+            GeneratedQuestion::MultipleChoice {
+                question,
+                choices,
+                answer,
+            } => {
+                // the answer may come as the letter of the right choice rather than its text
+                let answer = match answer.trim().as_bytes() {
+                    [letter @ b'A'..=b'Z'] => choices.get((letter - b'A') as usize)?.clone(),
+                    _ => answer.trim().to_string(),
+                };
+                // choices are listed by letter, so there can be no more than the alphabet has
+                if !(2..=MAX_CHOICES).contains(&choices.len())
+                    || !choices.iter().any(|c| c.trim() == answer)
+                {
+                    return None;
+                }
+                let listed = choices
+                    .iter()
+                    .enumerate()
+                    .map(|(i, c)| format!("{}) {}", (b'A' + i as u8) as char, c))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                (
+                    format!("{}\n{}", question, listed),
+                    answer,
+                    CardKind::Choice(choices),
+                )
+            }
+            GeneratedQuestion::TrueFalse { question, answer } => (
+                format!("True or false: {}", question),
+                String::from(if answer { "True" } else { "False" }),
+                CardKind::Choice(vec![String::from("True"), String::from("False")]),
+            ),
+        };
+
+        if question.trim().is_empty() || answer.is_empty() {
+            return None;
+        }
+
+        Some(FlashCardData {
+            card_id: None,
+            difficulty: 0,
+            answer,
+            question,
+            topic: subject.to_string(),
+            kind,
+        })
+    }
+}
+
+// Missed questions are saved as rather hard cards, since they were missed
+const PRACTICE_CARD_DIFFICULTY: i64 = 6;
+
+// A generated question answered wrong, skipped or timed out
+const MISSED_ANSWER: &str =
+    "quiz_answers.card_id IS NULL AND (quiz_answers.is_correct IS NULL OR quiz_answers.is_correct = 0)";
+
+/// Number of distinct questions missed in a practice quiz, see `save_missed`
+pub fn missed_count(quiz_id: i64) -> Result<usize, sqlite::Error> {
+    let db = db::get_db();
+    let mut statement = db.prepare(format!(
+        "SELECT COUNT(DISTINCT quiz_answers.question) AS n FROM quiz_answers
+        WHERE quiz_answers.quiz_id = ? AND {}",
+        MISSED_ANSWER
+    ))?;
+    statement.bind((1, quiz_id))?;
+    statement.next()?;
+    Ok(statement.read::<i64, _>("n")? as usize)
+}
+
+/// Stores the questions missed in a practice quiz of `user_id` as flashcards of its topic,
+/// returning how many were saved
+pub fn save_missed(quiz_id: i64, user_id: u64) -> Result<usize, sqlite::Error> {
+    let db = db::get_db();
+
+    let mut missed: Vec<(String, String, String, Option<String>)> = Vec::new();
+    let mut statement = db.prepare(format!(
+        "
+        SELECT DISTINCT quiz_answers.question, quiz_answers.reference_answer, quiz_reports.topic,
+            quiz_answers.choices
+        FROM quiz_answers
+        JOIN quiz_reports ON quiz_reports.quiz_id = quiz_answers.quiz_id
+        WHERE quiz_answers.quiz_id = ? AND quiz_reports.user_id = ? AND {}",
+        MISSED_ANSWER
+    ))?;
+    statement.bind((1, quiz_id))?;
+    statement.bind((2, user_id.to_string().as_str()))?;
+    while let State::Row = statement.next()? {
+        missed.push((
+            statement.read::<String, _>("question")?,
+            statement.read::<String, _>("reference_answer")?,
+            statement
+                .read::<Option<String>, _>("topic")?
+                .unwrap_or_default(),
+            statement.read::<Option<String>, _>("choices")?,
+        ));
+    }
+
+    // the same question is never stored twice, so pressing the button again is harmless.
+    // Choice questions keep their options, so they can still be answered by letter.
+    let mut saved = 0;
+    for (question, answer, topic, choices) in &missed {
+        let mut statement = db.prepare(
+            "
+            INSERT INTO flashcards (user_id, question, answer, topic, difficulty, card_type, choices)
+            SELECT ?, ?, ?, ?, ?, ?, ?
+            WHERE NOT EXISTS (
+                SELECT 1 FROM flashcards WHERE user_id = ? AND topic = ? AND question = ?
+            )
+            RETURNING card_id",
+        )?;
+        statement.bind((1, user_id.to_string().as_str()))?;
+        statement.bind((2, question.as_str()))?;
+        statement.bind((3, answer.as_str()))?;
+        statement.bind((4, topic.as_str()))?;
+        statement.bind((5, PRACTICE_CARD_DIFFICULTY))?;
+        statement.bind((6, if choices.is_some() { "choice" } else { "text" }))?;
+        statement.bind((7, choices.as_deref()))?;
+        statement.bind((8, user_id.to_string().as_str()))?;
+        statement.bind((9, topic.as_str()))?;
+        statement.bind((10, question.as_str()))?;
+        if let State::Row = statement.next()? {
+            saved += 1;
+        }
+    }
+
+    Ok(saved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{QuizData, QuizManager};
+
+    fn choice(choices: &[&str], answer: &str) -> GeneratedQuestion {
+        GeneratedQuestion::MultipleChoice {
+            question: String::from("Which gas do plants absorb?"),
+            choices: choices.iter().map(|c| c.to_string()).collect(),
+            answer: answer.to_string(),
+        }
+    }
+
+    #[test]
+    fn lists_the_choices_by_letter() {
+        let card = choice(&["O2", "CO2", "N2"], "B").into_card("bio").unwrap();
+        assert_eq!(card.answer, "CO2");
+        assert_eq!(
+            card.question,
+            "Which gas do plants absorb?\nA) O2\nB) CO2\nC) N2"
+        );
+        assert_eq!(card.topic, "bio");
+        assert!(card.card_id.is_none());
+        assert!(card.is_correct("b") && card.is_correct("co2"));
+
+        let card = GeneratedQuestion::TrueFalse {
+            question: String::from("The sun is a star"),
+            answer: true,
+        }
+        .into_card("astro")
+        .unwrap();
+        assert_eq!(card.question, "True or false: The sun is a star");
+        assert!(card.is_correct("T"));
+    }
+
+    #[test]
+    fn drops_malformed_questions() {
+        assert!(choice(&["O2"], "O2").into_card("bio").is_none());
+        assert!(choice(&["O2", "N2"], "CO2").into_card("bio").is_none());
+        assert!(choice(&["O2", "N2"], "D").into_card("bio").is_none());
+        let alphabet: Vec<String> = (0..27).map(|i| format!("choice {}", i)).collect();
+        let alphabet: Vec<&str> = alphabet.iter().map(String::as_str).collect();
+        assert!(choice(&alphabet, "choice 0").into_card("bio").is_none());
+        assert!(choice(&alphabet[..26], "Z").into_card("bio").is_some());
+
+        let blank = GeneratedQuestion::ShortAnswer {
+            question: String::from(" "),
+            answer: String::from("x"),
+        };
+        let test = GeneratedTest {
+            questions: vec![blank],
+        };
+        assert!(matches!(test.into_cards("bio"), Err(PracticeError::Empty)));
+    }
+
+    #[test]
+    fn saves_the_missed_questions_once() {
+        let user_id = 3601;
+        let cards = ["H2O", "NaCl", "CO2", "O3"]
+            .iter()
+            .map(|formula| {
+                GeneratedQuestion::ShortAnswer {
+                    question: format!("Formula of {}?", formula),
+                    answer: formula.to_string(),
+                }
+                .into_card("chem")
+                .unwrap()
+            })
+            .collect();
+        let info = QuizData {
+            user_id,
+            topic: String::from("chem"),
+            ..QuizData::default()
+        };
+        let mut quiz = QuizManager::from_cards(info, cards);
+        quiz.is_new();
+        quiz.get_question().unwrap();
+        // right with a hint, so it scores less than a point
+        assert!(quiz.use_hint(1, 1));
+        assert!(quiz.check_answer("H2O"));
+        quiz.get_question().unwrap();
+        assert!(!quiz.check_answer("NaCl2"));
+        quiz.get_question().unwrap();
+        quiz.skip();
+        quiz.get_question().unwrap();
+        quiz.time_out();
+        let quiz_id = quiz.get_quiz_id().unwrap();
+
+        assert_eq!(missed_count(quiz_id).unwrap(), 3);
+        assert_eq!(save_missed(quiz_id, user_id + 1).unwrap(), 0);
+        assert_eq!(save_missed(quiz_id, user_id).unwrap(), 3);
+        assert_eq!(save_missed(quiz_id, user_id).unwrap(), 0);
+
+        let db = db::get_db();
+        let mut statement = db
+            .prepare("SELECT question FROM flashcards WHERE user_id = ? ORDER BY question")
+            .unwrap();
+        statement.bind((1, user_id.to_string().as_str())).unwrap();
+        let mut saved = Vec::new();
+        while let State::Row = statement.next().unwrap() {
+            saved.push(statement.read::<String, _>("question").unwrap());
+        }
+        assert_eq!(
+            saved,
+            ["Formula of CO2?", "Formula of NaCl?", "Formula of O3?"]
+        );
+    }
+}
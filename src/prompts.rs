@@ -0,0 +1,438 @@
+use crate::constants;
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum PromptError {
+    #[error("cannot read {0}: {1}")]
+    Io(String, std::io::Error),
+    #[error("invalid prompt file: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("unknown template {0}")]
+    UnknownTemplate(String),
+    #[error("template {template}: {reason}")]
+    Syntax { template: String, reason: String },
+    #[error("template {template} uses the unknown variable {{{variable}}}")]
+    UnknownVariable { template: String, variable: String },
+    #[error("template {template} does not use the variable {{{variable}}}")]
+    MissingVariable { template: String, variable: String },
+}
+
+/// A prompt template, overridable in the `[templates]` section of the prompt file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Template {
+    Summarize,
+    SummarizeChunk,
+    FormatTldr,
+    FormatBullets,
+    FormatOutline,
+    FormatStudyNotes,
+    Explain,
+    Define,
+    Translate,
+    Compare,
+    Hint,
+    Why,
+    Practice,
+    FollowUp,
+    JsonSchema,
+    JsonRetry,
+}
+
+impl Template {
+    pub const ALL: [Template; 16] = [
+        Template::Summarize,
+        Template::SummarizeChunk,
+        Template::FormatTldr,
+        Template::FormatBullets,
+        Template::FormatOutline,
+        Template::FormatStudyNotes,
+        Template::Explain,
+        Template::Define,
+        Template::Translate,
+        Template::Compare,
+        Template::Hint,
+        Template::Why,
+        Template::Practice,
+        Template::FollowUp,
+        Template::JsonSchema,
+        Template::JsonRetry,
+    ];
+
+    /// Name of the template in the prompt file
+    pub fn key(self) -> &'static str {
+        match self {
+            Template::Summarize => "summarize",
+            Template::SummarizeChunk => "summarize_chunk",
+            Template::FormatTldr => "format_tldr",
+            Template::FormatBullets => "format_bullets",
+            Template::FormatOutline => "format_outline",
+            Template::FormatStudyNotes => "format_study_notes",
+            Template::Explain => "explain",
+            Template::Define => "define",
+            Template::Translate => "translate",
+            Template::Compare => "compare",
+            Template::Hint => "hint",
+            Template::Why => "why",
+            Template::Practice => "practice",
+            Template::FollowUp => "followup",
+            Template::JsonSchema => "json_schema",
+            Template::JsonRetry => "json_retry",
+        }
+    }
+
+    pub fn from_key(key: &str) -> Option<Template> {
+        Template::ALL.into_iter().find(|t| t.key() == key)
+    }
+
+    // The built-in text of the template, where `{{` and `}}` are literal braces
+    fn default_text(self) -> &'static str {
+        match self {
+            Template::Summarize => "Summarize the below text. {format}\n{text}",
+            Template::SummarizeChunk => "Summarize the below text. This is one part of a longer text, keep every important fact.\n\n{text}",
+            Template::FormatTldr => "Answer with a TL;DR of two or three sentences at most.",
+            Template::FormatBullets => "Answer with a bullet list of the key points.",
+            Template::FormatOutline => "Answer with a hierarchical outline, using headings and nested lists.",
+            Template::FormatStudyNotes => "Answer with study notes: key concepts with short definitions, important facts, and a few review questions at the end.",
+            Template::Explain => "Explain the following concept to a {level} student:\n{concept}",
+            Template::Define => "Provide a formal and short definition for the following concept:\n{concept}",
+            Template::Translate => "Translate the provided text into {language}.\nText: {text}",
+            Template::Compare => "Compare the following concepts, highlighting the similiraties and differences.\nFirst concept: {concept1}\nSecond concept: {concept2}",
+            Template::Hint => "Give a short conceptual hint that helps recalling the answer to the question below. Never reveal the answer itself.\nQuestion: {question}\nAnswer: {answer}",
+            Template::Why => "Briefly explain the gap between the student answer and the reference answer to the question below, and what the student is missing.\nQuestion: {question}\nReference answer: {reference}\nStudent answer: {answer}",
+            Template::Practice => "Write a practice test on the subject below, mixing short answer, multiple choice and true/false questions. Short answers must be one to three words, and multiple choice questions have four choices.\nSubject: {subject}\nNumber of questions: {count}",
+            Template::FollowUp => "{text}",
+            Template::JsonSchema => "Reply with JSON only, without any other text, matching this JSON schema:\n{schema}",
+            Template::JsonRetry => "Your reply could not be read: {error}\nReply again with the JSON only, matching the schema.",
+        }
+    }
+
+    // The variables the template must use, and the ones it may use
+    fn variables(self) -> (&'static [&'static str], &'static [&'static str]) {
+        match self {
+            Template::Summarize => (&["text"], &["format"]),
+            Template::SummarizeChunk => (&["text"], &[]),
+            Template::FormatTldr => (&[], &[]),
+            Template::FormatBullets => (&[], &[]),
+            Template::FormatOutline => (&[], &[]),
+            Template::FormatStudyNotes => (&[], &[]),
+            Template::Explain => (&["concept"], &["level"]),
+            Template::Define => (&["concept"], &[]),
+            Template::Translate => (&["language", "text"], &[]),
+            Template::Compare => (&["concept1", "concept2"], &[]),
+            Template::Hint => (&["question", "answer"], &[]),
+            Template::Why => (&["question", "reference", "answer"], &[]),
+            Template::Practice => (&["subject", "count"], &[]),
+            Template::FollowUp => (&["text"], &[]),
+            Template::JsonSchema => (&["schema"], &[]),
+            Template::JsonRetry => (&["error"], &[]),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Text(String),
+    Variable(String),
+}
+
+/// The prompts sent to the model: the system prompt and one template per request kind
+#[derive(Debug)]
+pub struct Prompts {
+    system: String,
+    templates: HashMap<Template, Vec<Segment>>,
+    /// Changes whenever a prompt does, so that cached answers to old prompts are not used
+    version: String,
+}
+
+// Layout of the prompt file, where every entry is optional
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PromptFile {
+    system: Option<String>,
+    #[serde(default)]
+    templates: HashMap<String, String>,
+}
+
+static PROMPTS: OnceCell<Prompts> = OnceCell::new();
+
+impl Prompts {
+    /// The built-in prompts, overridden by the ones of the file at `path` if given
+    pub fn load(path: Option<&str>) -> Result<Prompts, PromptError> {
+        let file = match path {
+            Some(path) => {
+                let text =
+                    fs::read_to_string(path).map_err(|e| PromptError::Io(path.to_string(), e))?;
+                toml::from_str(&text)?
+            }
+            None => PromptFile {
+                system: None,
+                templates: HashMap::new(),
+            },
+        };
+        Prompts::from_file(file)
+    }
+
+    // Checks the templates of a prompt file, the missing ones keeping their built-in text
+    fn from_file(file: PromptFile) -> Result<Prompts, PromptError> {
+        if let Some(name) = file
+            .templates
+            .keys()
+            .find(|name| Template::from_key(name).is_none())
+        {
+            return Err(PromptError::UnknownTemplate(name.clone()));
+        }
+
+        let system = file
+            .system
+            .unwrap_or_else(|| constants::SYSTEM_PROMPT.to_string());
+        let mut hash = fnv1a(FNV_OFFSET_BASIS, system.as_bytes());
+
+        let mut templates = HashMap::new();
+        for template in Template::ALL {
+            let name = template.key();
+            let (required, optional) = template.variables();
+            let text = file
+                .templates
+                .get(name)
+                .map_or(template.default_text(), String::as_str);
+            hash = fnv1a(fnv1a(hash, name.as_bytes()), text.as_bytes());
+            let segments = parse(name, text)?;
+            for segment in &segments {
+                if let Segment::Variable(variable) = segment {
+                    if !required.contains(&variable.as_str())
+                        && !optional.contains(&variable.as_str())
+                    {
+                        return Err(PromptError::UnknownVariable {
+                            template: name.to_string(),
+                            variable: variable.clone(),
+                        });
+                    }
+                }
+            }
+            // a template leaving out a required variable would drop the user's input
+            for variable in required {
+                if !segments.contains(&Segment::Variable(variable.to_string())) {
+                    return Err(PromptError::MissingVariable {
+                        template: name.to_string(),
+                        variable: variable.to_string(),
+                    });
+                }
+            }
+            templates.insert(template, segments);
+        }
+
+        Ok(Prompts {
+            system,
+            templates,
+            version: format!("{:016x}", hash),
+        })
+    }
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+// FNV-1a, a hash that stays the same across builds so that the version survives a Rust update.
+// Every input ends with 0xff, a byte UTF-8 never uses, so that moving text between inputs changes it.
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().chain(&[0xff]).fold(hash, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(FNV_PRIME)
+    })
+}
+
+// Splits a template in text and `{variable}` segments
+fn parse(name: &str, template: &str) -> Result<Vec<Segment>, PromptError> {
+    let syntax = |reason: &str| PromptError::Syntax {
+        template: name.to_string(),
+        reason: reason.to_string(),
+    };
+
+    let mut segments = Vec::new();
+    let mut text = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                text.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                text.push('}');
+            }
+            '{' => {
+                let mut variable = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) if c.is_ascii_alphanumeric() || c == '_' => variable.push(c),
+                        Some(_) => return Err(syntax("invalid variable name, use {{ for a brace")),
+                        None => return Err(syntax("unclosed {")),
+                    }
+                }
+                if variable.is_empty() {
+                    return Err(syntax("empty variable {}"));
+                }
+                segments.push(Segment::Text(std::mem::take(&mut text)));
+                segments.push(Segment::Variable(variable));
+            }
+            '}' => return Err(syntax("unmatched }, use }} for a brace")),
+            c => text.push(c),
+        }
+    }
+    segments.push(Segment::Text(text));
+    Ok(segments)
+}
+
+/// Loads and checks the prompts once at startup, see `Prompts::load`
+pub fn init(path: Option<&str>) -> Result<(), PromptError> {
+    let prompts = Prompts::load(path)?;
+    // a second call keeps the prompts loaded first
+    let _ = PROMPTS.set(prompts);
+    Ok(())
+}
+
+fn prompts() -> &'static Prompts {
+    PROMPTS.get_or_init(|| Prompts::load(None).expect("invalid built-in prompts"))
+}
+
+/// The system prompt of every request
+pub fn system() -> &'static str {
+    &prompts().system
+}
+
+/// Identifies the prompts in use, see `Prompts::version`
+pub fn version() -> &'static str {
+    &prompts().version
+}
+
+/// Fills `template` with `variables`; variables it does not use are ignored
+pub fn render(template: Template, variables: &[(&str, &str)]) -> String {
+    prompts().templates[&template]
+        .iter()
+        .map(|segment| match segment {
+            Segment::Text(text) => text.as_str(),
+            Segment::Variable(variable) => variables
+                .iter()
+                .find(|(name, _)| name == variable)
+                .map_or("", |(_, value)| *value),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_toml(text: &str) -> Result<Prompts, PromptError> {
+        Prompts::from_file(toml::from_str(text).unwrap())
+    }
+
+    #[test]
+    fn splits_templates_in_text_and_variables() {
+        assert_eq!(
+            parse("t", "a {{b}} {c}!").unwrap(),
+            vec![
+                Segment::Text(String::from("a {b} ")),
+                Segment::Variable(String::from("c")),
+                Segment::Text(String::from("!")),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_templates() {
+        assert!(matches!(parse("t", "{a"), Err(PromptError::Syntax { .. })));
+        assert!(matches!(parse("t", "a}"), Err(PromptError::Syntax { .. })));
+        assert!(matches!(parse("t", "{}"), Err(PromptError::Syntax { .. })));
+        assert!(matches!(
+            parse("t", "{a b}"),
+            Err(PromptError::Syntax { .. })
+        ));
+    }
+
+    #[test]
+    fn overrides_only_the_given_templates() {
+        let prompts = from_toml(
+            r#"
+            system = "Sei QuizPal"
+            [templates]
+            define = "Definisci {concept}"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(prompts.system, "Sei QuizPal");
+        assert_eq!(
+            prompts.templates[&Template::Define],
+            parse("define", "Definisci {concept}").unwrap()
+        );
+        assert_eq!(
+            prompts.templates[&Template::Explain],
+            parse("explain", Template::Explain.default_text()).unwrap()
+        );
+    }
+
+    #[test]
+    fn checks_the_variables_of_every_template() {
+        assert!(matches!(
+            from_toml("[templates]\nmindmap = \"{topic}\""),
+            Err(PromptError::UnknownTemplate(name)) if name == "mindmap"
+        ));
+        assert!(matches!(
+            from_toml("[templates]\ndefine = \"{concept} {level}\""),
+            Err(PromptError::UnknownVariable { variable, .. }) if variable == "level"
+        ));
+        assert!(matches!(
+            from_toml("[templates]\ntranslate = \"Into {language}\""),
+            Err(PromptError::MissingVariable { variable, .. }) if variable == "text"
+        ));
+        assert!(from_toml("[templates]\nexplain = \"{concept}\"").is_ok());
+    }
+
+    #[test]
+    fn changes_the_version_with_the_prompts() {
+        let builtin = Prompts::load(None).unwrap();
+        let edited = from_toml("[templates]\ndefine = \"Define {concept}\"").unwrap();
+        assert_eq!(builtin.version, from_toml("").unwrap().version);
+        assert_ne!(builtin.version, edited.version);
+    }
+
+    #[test]
+    fn hashes_the_prompts_the_same_on_every_build() {
+        // the FNV-1a test vector for "a", without the trailing separator
+        assert_eq!(
+            (FNV_OFFSET_BASIS ^ u64::from(b'a')).wrapping_mul(FNV_PRIME),
+            0xaf63_dc4c_8601_ec8c
+        );
+        assert_ne!(
+            fnv1a(fnv1a(FNV_OFFSET_BASIS, b"ab"), b"c"),
+            fnv1a(fnv1a(FNV_OFFSET_BASIS, b"a"), b"bc")
+        );
+        let version = Prompts::load(None).unwrap().version;
+        assert_eq!(version, from_toml("").unwrap().version);
+        assert_eq!(version.len(), 16);
+    }
+
+    #[test]
+    fn names_every_template() {
+        for template in Template::ALL {
+            assert_eq!(Template::from_key(template.key()), Some(template));
+        }
+    }
+
+    #[test]
+    fn renders_the_variables() {
+        assert_eq!(
+            render(
+                Template::FollowUp,
+                &[("text", "and {this}?"), ("unused", "x")]
+            ),
+            "and {this}?"
+        );
+        assert!(render(Template::Explain, &[("concept", "entropy")]).ends_with(":\nentropy"));
+    }
+}
@@ -0,0 +1,390 @@
+use pulldown_cmark::{CodeBlockKind, Event, HeadingLevel, Options, Parser, Tag, TagEnd};
+
+/// Escapes the characters that have a meaning in Telegram HTML
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Renders the CommonMark output of the model as Telegram HTML, to be sent with
+/// `ParseMode::Html`. Telegram has no headings, lists or tables, so they are written out as text.
+pub fn markdown_to_html(markdown: &str) -> String {
+    let mut out = String::with_capacity(markdown.len());
+    // one entry per open list, holding the number of the next item of ordered lists
+    let mut lists: Vec<Option<u64>> = Vec::new();
+
+    let options = Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TABLES;
+    for event in Parser::new_ext(markdown, options) {
+        match event {
+            Event::Start(Tag::Strong) => out.push_str("<b>"),
+            Event::End(TagEnd::Strong) => out.push_str("</b>"),
+            Event::Start(Tag::Emphasis) => out.push_str("<i>"),
+            Event::End(TagEnd::Emphasis) => out.push_str("</i>"),
+            Event::Start(Tag::Strikethrough) => out.push_str("<s>"),
+            Event::End(TagEnd::Strikethrough) => out.push_str("</s>"),
+            Event::Start(Tag::Heading { level, .. }) => {
+                if level == HeadingLevel::H1 || level == HeadingLevel::H2 {
+                    out.push_str("<b><u>");
+                } else {
+                    out.push_str("<b>");
+                }
+            }
+            Event::End(TagEnd::Heading(level)) => {
+                if level == HeadingLevel::H1 || level == HeadingLevel::H2 {
+                    out.push_str("</u></b>");
+                } else {
+                    out.push_str("</b>");
+                }
+                out.push_str("\n\n");
+            }
+            Event::Start(Tag::BlockQuote(_)) => out.push_str("<blockquote>"),
+            Event::End(TagEnd::BlockQuote(_)) => {
+                trim_end_newlines(&mut out);
+                out.push_str("</blockquote>\n\n");
+            }
+            Event::Start(Tag::CodeBlock(kind)) => match kind {
+                CodeBlockKind::Fenced(lang) if !lang.is_empty() => out.push_str(&format!(
+                    "<pre><code class=\"language-{}\">",
+                    escape_html(&lang)
+                )),
+                _ => out.push_str("<pre><code>"),
+            },
+            Event::End(TagEnd::CodeBlock) => {
+                trim_end_newlines(&mut out);
+                out.push_str("</code></pre>\n\n");
+            }
+            Event::Start(Tag::List(first)) => {
+                if !lists.is_empty() {
+                    trim_end_newlines(&mut out);
+                    out.push('\n');
+                }
+                lists.push(first);
+            }
+            Event::End(TagEnd::List(_)) => {
+                lists.pop();
+                if lists.is_empty() {
+                    trim_end_newlines(&mut out);
+                    out.push_str("\n\n");
+                }
+            }
+            Event::Start(Tag::Item) => {
+                out.push_str(&"    ".repeat(lists.len().saturating_sub(1)));
+                match lists.last_mut() {
+                    Some(Some(n)) => {
+                        out.push_str(&format!("{}. ", n));
+                        *n += 1;
+                    }
+                    _ => out.push_str("• "),
+                }
+            }
+            Event::End(TagEnd::Item) => {
+                trim_end_newlines(&mut out);
+                out.push('\n');
+            }
+            Event::Start(Tag::Link { dest_url, .. }) => {
+                out.push_str(&format!("<a href=\"{}\">", escape_html(&dest_url)))
+            }
+            Event::End(TagEnd::Link) => out.push_str("</a>"),
+            Event::End(TagEnd::Paragraph) => {
+                // items of tight lists hold their text without paragraphs
+                if lists.is_empty() {
+                    out.push_str("\n\n");
+                } else {
+                    out.push('\n');
+                }
+            }
+            Event::End(TagEnd::TableCell) => out.push_str(" | "),
+            Event::End(TagEnd::TableHead) | Event::End(TagEnd::TableRow) => {
+                while out.ends_with(' ') || out.ends_with('|') {
+                    out.pop();
+                }
+                out.push('\n');
+            }
+            Event::End(TagEnd::Table) => out.push('\n'),
+            Event::Code(code) => {
+                out.push_str("<code>");
+                out.push_str(&escape_html(&code));
+                out.push_str("</code>");
+            }
+            Event::Text(text) | Event::Html(text) | Event::InlineHtml(text) => {
+                out.push_str(&escape_html(&text))
+            }
+            Event::SoftBreak => out.push(' '),
+            Event::HardBreak => out.push('\n'),
+            Event::Rule => out.push_str("──────────\n\n"),
+            _ => {}
+        }
+    }
+
+    out.trim().to_string()
+}
+
+fn trim_end_newlines(out: &mut String) {
+    while out.ends_with('\n') {
+        out.pop();
+    }
+}
+
+/// The text of a part of rendered HTML without its formatting, for when Telegram rejects the
+/// HTML. Links are followed by their address.
+pub fn html_to_plain(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut links: Vec<&str> = Vec::new();
+    let mut rest = html;
+
+    while let Some(start) = rest.find('<') {
+        out.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('>').map(|end| start + end) else {
+            break;
+        };
+        let tag = &rest[start..=end];
+        if let Some(url) = tag
+            .strip_prefix("<a href=\"")
+            .and_then(|t| t.strip_suffix("\">"))
+        {
+            links.push(url);
+        } else if tag == "</a>" {
+            match links.pop() {
+                Some(url) if !url.is_empty() => out.push_str(&format!(" ({})", url)),
+                _ => {}
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+
+    // &amp; goes last, so that an escaped entity such as "&amp;lt;" stays as written
+    out.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&amp;", "&")
+}
+
+// Room kept in every part for its number and the tags closed at the cut
+const PART_MARGIN: usize = 96;
+
+/// Splits a message longer than `limit` into numbered parts ("1/3"), preferably between
+/// paragraphs, then lines, sentences and words. With `html`, tags and entities are never
+/// cut, and the tags open at a cut are closed at the end of a part and reopened in the next.
+pub fn split_message(text: &str, limit: usize, html: bool) -> Vec<String> {
+    let parts = split_text(text, limit, html);
+
+    let count = parts.len();
+    if count == 1 {
+        return parts;
+    }
+    parts
+        .into_iter()
+        .enumerate()
+        .map(|(i, part)| format!("{}/{}\n{}", i + 1, count, part))
+        .collect()
+}
+
+/// Splits a text in pieces of at most `limit` characters at the best boundaries, like
+/// `split_message` but without numbering the pieces. Characters are counted in UTF-16 code
+/// units like Telegram does, so an emoji counts twice.
+pub fn split_text(text: &str, limit: usize, html: bool) -> Vec<String> {
+    let budget = limit.saturating_sub(PART_MARGIN).max(1);
+    let mut parts = Vec::new();
+    let mut rest = text.trim().to_string();
+
+    // once split, the last part also needs room for its number
+    while rest.encode_utf16().count() > if parts.is_empty() { limit } else { budget } {
+        let (head, tail) = cut(&rest, budget, html);
+        parts.push(head);
+        rest = tail;
+    }
+    parts.push(rest);
+    parts
+}
+
+// How good a cut right after `before` is; cuts inside formatting come last
+fn boundary_priority(before: &str, in_entity: bool) -> u8 {
+    let priority = if before.ends_with("\n\n") {
+        4
+    } else if before.ends_with('\n') {
+        3
+    } else if before.ends_with(". ") || before.ends_with("! ") || before.ends_with("? ") {
+        2
+    } else if before.ends_with(' ') {
+        1
+    } else {
+        return 0;
+    };
+    if in_entity {
+        priority
+    } else {
+        priority + 4
+    }
+}
+
+// Tags open at some point of a message, as (name, full opening tag)
+type OpenTags = Vec<(String, String)>;
+
+// Splits `text` in a head of about `budget` characters and the rest
+fn cut(text: &str, budget: usize, html: bool) -> (String, String) {
+    let mut open: OpenTags = Vec::new();
+    let mut best: Option<(usize, u8, OpenTags)> = None;
+    let mut last = 0;
+    // the tags open before the last unit, when that unit is a tag
+    let mut open_before_last: Option<OpenTags> = None;
+    let mut count = 0;
+    let mut i = 0;
+
+    while i < text.len() && count < budget {
+        // cuts too close to the start would make tiny parts
+        if count >= budget / 4 {
+            let priority = boundary_priority(&text[..i], !open.is_empty());
+            if priority > 0 && best.as_ref().is_none_or(|(_, p, _)| priority >= *p) {
+                best = Some((i, priority, open.clone()));
+            }
+        }
+        last = i;
+
+        let c = text[i..].chars().next().unwrap();
+        let unit_end = match c {
+            '<' if html => text[i..].find('>').map(|end| i + end + 1),
+            '&' if html => text[i..].find(';').map(|end| i + end + 1),
+            _ => None,
+        }
+        .unwrap_or(i + c.len_utf8());
+
+        let unit = &text[i..unit_end];
+        open_before_last = None;
+        if html && unit.starts_with("</") {
+            open_before_last = Some(open.clone());
+            open.pop();
+        } else if html && unit.starts_with('<') && unit.len() > 1 {
+            open_before_last = Some(open.clone());
+            let name: String = unit[1..]
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric())
+                .collect();
+            open.push((name, unit.to_string()));
+        }
+
+        count += unit.encode_utf16().count();
+        i = unit_end;
+    }
+
+    let (position, stack) = match best {
+        Some((position, _, stack)) => (position, stack),
+        // a single unit longer than the budget is kept whole rather than never cut
+        None if last == 0 => (i, open),
+        None => (last, open_before_last.unwrap_or(open)),
+    };
+
+    let mut head = text[..position].trim_end().to_string();
+    let mut tail = String::new();
+    for (name, _) in stack.iter().rev() {
+        head.push_str(&format!("</{}>", name));
+    }
+    for (_, tag) in &stack {
+        tail.push_str(tag);
+    }
+    tail.push_str(text[position..].trim_start());
+
+    (head, tail)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaves_short_messages_alone() {
+        assert_eq!(
+            split_message("  short answer \n", 100, true),
+            vec!["short answer"]
+        );
+    }
+
+    #[test]
+    fn numbers_the_parts_and_keeps_them_under_the_limit() {
+        let paragraph = "word ".repeat(40);
+        let text = [paragraph.trim(); 10].join("\n\n");
+        let parts = split_message(&text, 400, false);
+
+        assert!(parts.len() > 1);
+        for (i, part) in parts.iter().enumerate() {
+            assert!(part.starts_with(&format!("{}/{}\n", i + 1, parts.len())));
+            assert!(part.chars().count() <= 400);
+        }
+    }
+
+    #[test]
+    fn counts_characters_like_telegram() {
+        let text = "🙂 ".repeat(150);
+        let parts = split_message(&text, 400, false);
+        assert!(parts.len() > 1);
+        for part in &parts {
+            assert!(part.encode_utf16().count() <= 400, "{}", part);
+        }
+    }
+
+    #[test]
+    fn cuts_between_paragraphs_first() {
+        let paragraph = "word ".repeat(40);
+        let text = [paragraph.trim(); 3].join("\n\n");
+        for part in split_text(&text, 400, false) {
+            assert!(part.starts_with("word") && part.ends_with("word"));
+            assert_eq!(part.matches("word").count() % 40, 0);
+        }
+    }
+
+    #[test]
+    fn carries_open_tags_across_cuts() {
+        let text = format!("<b><i>{}</i></b>", "bold words ".repeat(60).trim());
+        let parts = split_text(&text, 300, true);
+
+        assert!(parts.len() > 1);
+        for part in &parts {
+            assert!(part.starts_with("<b><i>"), "{}", part);
+            assert!(part.ends_with("</i></b>"), "{}", part);
+        }
+    }
+
+    #[test]
+    fn strips_the_formatting_of_a_part() {
+        let html = markdown_to_html("**Bold** & [docs](https://a.b/?x=1&y=2) `<tag>`");
+        assert_eq!(
+            html_to_plain(&html),
+            "Bold & docs (https://a.b/?x=1&y=2) <tag>"
+        );
+        assert_eq!(html_to_plain("1/2\n<b>cut</b>"), "1/2\ncut");
+    }
+
+    #[test]
+    fn falls_back_to_the_same_text_part_by_part() {
+        let text = format!(
+            "**{}**\n\n{}",
+            "bold ".repeat(80).trim(),
+            "&amp; ".repeat(80)
+        );
+        let html = markdown_to_html(&text);
+        for part in split_message(&html, 300, true) {
+            let plain = html_to_plain(&part);
+            assert!(plain.chars().count() <= part.chars().count());
+            assert!(
+                !plain.contains('<') && !plain.contains("&amp;amp;"),
+                "{}",
+                plain
+            );
+        }
+    }
+
+    #[test]
+    fn never_cuts_inside_an_entity_or_a_tag() {
+        let text = format!(
+            "{}<a href=\"https://example.com\">link</a>",
+            "&amp;".repeat(200)
+        );
+        for part in split_text(&text, 150, true) {
+            let unclosed_entity = part.rfind('&').is_some_and(|at| !part[at..].contains(';'));
+            let unclosed_tag = part.rfind('<').is_some_and(|at| !part[at..].contains('>'));
+            assert!(!unclosed_entity && !unclosed_tag, "{}", part);
+        }
+    }
+}
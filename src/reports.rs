@@ -0,0 +1,392 @@
+use crate::db;
+use crate::types::QuizState;
+
+use sqlite::{State, Statement};
+
+/// One question of a quiz as logged in `quiz_answers`
+#[derive(Clone)]
+pub struct AnswerRecord {
+    pub answer_id: i64,
+    pub card_id: Option<i64>,
+    pub position: i64,
+    pub question: String,
+    pub reference_answer: String,
+    pub given_answer: Option<String>,
+    pub is_correct: Option<bool>,
+    pub skipped: bool,
+    pub credit: f64,
+    pub response_time_ms: Option<i64>,
+    pub hints_used: i64,
+    pub timed_out: bool,
+}
+
+impl AnswerRecord {
+    const COLUMNS: &'static str = "
+        quiz_answers.answer_id, quiz_answers.card_id, quiz_answers.position,
+        quiz_answers.question, quiz_answers.reference_answer, quiz_answers.given_answer,
+        quiz_answers.is_correct, quiz_answers.skipped, quiz_answers.credit,
+        quiz_answers.response_time_ms, quiz_answers.hints_used, quiz_answers.timed_out";
+
+    fn read(statement: &Statement) -> Result<Self, sqlite::Error> {
+        Ok(AnswerRecord {
+            answer_id: statement.read::<i64, _>("answer_id")?,
+            card_id: statement.read::<Option<i64>, _>("card_id")?,
+            position: statement.read::<i64, _>("position")?,
+            question: statement.read::<String, _>("question")?,
+            reference_answer: statement.read::<String, _>("reference_answer")?,
+            given_answer: statement.read::<Option<String>, _>("given_answer")?,
+            is_correct: statement
+                .read::<Option<i64>, _>("is_correct")?
+                .map(|c| c != 0),
+            skipped: statement.read::<i64, _>("skipped")? != 0,
+            credit: statement.read::<f64, _>("credit")?,
+            response_time_ms: statement.read::<Option<i64>, _>("response_time_ms")?,
+            hints_used: statement.read::<i64, _>("hints_used")?,
+            timed_out: statement.read::<i64, _>("timed_out")? != 0,
+        })
+    }
+}
+
+pub struct QuizReport {
+    pub quiz_id: i64,
+    pub topic: String,
+    pub mode: String,
+    pub score: f64,
+    pub total_questions: i64,
+    pub answered_questions: i64,
+    pub skipped_questions: i64,
+    pub timed_out_questions: i64,
+    pub taken_at: String,
+    pub answers: Vec<AnswerRecord>,
+}
+
+impl QuizReport {
+    /// Replays the quiz question by question, highlighting every miss
+    pub fn render(&self) -> String {
+        let mut text = format!(
+            "📋 Quiz #{} on {} ({}, {})\nScore: {}/{} · Skipped: {} · Timed out: {}\n",
+            self.quiz_id,
+            self.topic,
+            self.mode,
+            self.taken_at,
+            self.score,
+            self.answered_questions,
+            self.skipped_questions,
+            self.timed_out_questions
+        );
+
+        if self.answers.is_empty() {
+            text.push_str("\nNo answers were recorded for this quiz.");
+            return text;
+        }
+
+        for answer in &self.answers {
+            let mark = match (answer.skipped, answer.timed_out, answer.is_correct) {
+                (true, _, _) => "⏭️",
+                (_, true, _) => "⌛",
+                (_, _, Some(true)) => "✅",
+                _ => "❌",
+            };
+            text.push_str(&format!(
+                "\n{} {}. {}\n",
+                mark, answer.position, answer.question
+            ));
+
+            match &answer.given_answer {
+                Some(given) if answer.is_correct != Some(true) => {
+                    text.push_str(&format!(
+                        "   Your answer: {}\n   ➡️ Correct answer: {}\n",
+                        given, answer.reference_answer
+                    ));
+                }
+                Some(given) => text.push_str(&format!("   Your answer: {}\n", given)),
+                None => text.push_str(&format!(
+                    "   ➡️ Correct answer: {}\n",
+                    answer.reference_answer
+                )),
+            }
+
+            let mut details = Vec::new();
+            if let Some(ms) = answer.response_time_ms {
+                details.push(format!("{:.1}s", ms as f64 / 1000.0));
+            }
+            if answer.hints_used > 0 {
+                details.push(format!("{} hint(s)", answer.hints_used));
+            }
+            if !details.is_empty() {
+                text.push_str(&format!("   ⏱️ {}\n", details.join(" · ")));
+            }
+        }
+
+        text
+    }
+}
+
+pub fn load_report(quiz_id: i64, user_id: u64) -> Result<Option<QuizReport>, sqlite::Error> {
+    let db = db::get_db();
+
+    let mut report = {
+        let mut statement = db.prepare(
+            "
+            SELECT quiz_id, topic, mode, score, total_questions, answered_questions,
+                   skipped_questions, timed_out_questions, taken_at
+            FROM quiz_reports
+            WHERE quiz_id = ? AND user_id = ?",
+        )?;
+        statement.bind((1, quiz_id))?;
+        statement.bind((2, user_id.to_string().as_str()))?;
+
+        if let State::Done = statement.next()? {
+            return Ok(None);
+        }
+
+        QuizReport {
+            quiz_id: statement.read::<i64, _>("quiz_id")?,
+            topic: statement
+                .read::<Option<String>, _>("topic")?
+                .unwrap_or_default(),
+            mode: statement.read::<String, _>("mode")?,
+            score: statement.read::<Option<f64>, _>("score")?.unwrap_or(0.0),
+            total_questions: statement
+                .read::<Option<i64>, _>("total_questions")?
+                .unwrap_or(0),
+            answered_questions: statement
+                .read::<Option<i64>, _>("answered_questions")?
+                .unwrap_or(0),
+            skipped_questions: statement.read::<i64, _>("skipped_questions")?,
+            timed_out_questions: statement.read::<i64, _>("timed_out_questions")?,
+            taken_at: statement.read::<String, _>("taken_at")?,
+            answers: Vec::new(),
+        }
+    };
+
+    let mut statement = db.prepare(format!(
+        "SELECT {} FROM quiz_answers WHERE quiz_id = ? ORDER BY answer_id",
+        AnswerRecord::COLUMNS
+    ))?;
+    statement.bind((1, quiz_id))?;
+    while let State::Row = statement.next()? {
+        report.answers.push(AnswerRecord::read(&statement)?);
+    }
+
+    Ok(Some(report))
+}
+
+/// Loads a single logged answer, provided it belongs to a quiz of `user_id`
+pub fn load_answer(answer_id: i64, user_id: u64) -> Result<Option<AnswerRecord>, sqlite::Error> {
+    let db = db::get_db();
+    let mut statement = db.prepare(format!(
+        "
+        SELECT {}
+        FROM quiz_answers
+        JOIN quiz_reports ON quiz_reports.quiz_id = quiz_answers.quiz_id
+        WHERE quiz_answers.answer_id = ? AND quiz_reports.user_id = ?",
+        AnswerRecord::COLUMNS
+    ))?;
+    statement.bind((1, answer_id))?;
+    statement.bind((2, user_id.to_string().as_str()))?;
+
+    match statement.next()? {
+        State::Row => Ok(Some(AnswerRecord::read(&statement)?)),
+        State::Done => Ok(None),
+    }
+}
+
+/// A line of `/history`
+pub struct QuizSummary {
+    pub quiz_id: i64,
+    pub topic: String,
+    pub mode: String,
+    pub score: f64,
+    pub total_questions: i64,
+    pub answered_questions: i64,
+    pub state: QuizState,
+    pub position: Option<i64>,
+    /// False for practice tests, whose generated questions are not stored
+    pub resumable: bool,
+    pub taken_at: String,
+}
+
+impl QuizSummary {
+    pub fn render(&self) -> String {
+        match self.state {
+            QuizState::Running => format!(
+                "▶️ #{} {} ({}) · {} · in progress at question {}/{}",
+                self.quiz_id,
+                self.topic,
+                self.mode,
+                self.taken_at,
+                self.position.unwrap_or(0) + 1,
+                self.total_questions
+            ),
+            QuizState::Stopped if !self.resumable => format!(
+                "⏹️ #{} {} ({}) · {} · stopped at question {}/{}",
+                self.quiz_id,
+                self.topic,
+                self.mode,
+                self.taken_at,
+                self.position.unwrap_or(0) + 1,
+                self.total_questions
+            ),
+            QuizState::Stopped => format!(
+                "⏸️ #{} {} ({}) · {} · stopped at question {}/{} · /resume {}",
+                self.quiz_id,
+                self.topic,
+                self.mode,
+                self.taken_at,
+                self.position.unwrap_or(0) + 1,
+                self.total_questions,
+                self.quiz_id
+            ),
+            QuizState::Finished => format!(
+                "✅ #{} {} ({}) · {} · completed, score {}/{}",
+                self.quiz_id,
+                self.topic,
+                self.mode,
+                self.taken_at,
+                self.score,
+                self.answered_questions
+            ),
+        }
+    }
+}
+
+/// Most recent quizzes of a user with at least one card, newest first
+pub fn load_history(user_id: u64, limit: i64) -> Result<Vec<QuizSummary>, sqlite::Error> {
+    let db = db::get_db();
+    let mut statement = db.prepare(
+        "
+        SELECT quiz_id, topic, mode, score, total_questions, answered_questions, state,
+               position, card_queue IS NOT NULL AS resumable, taken_at
+        FROM quiz_reports
+        WHERE user_id = ? AND total_questions > 0
+        ORDER BY quiz_id DESC
+        LIMIT ?",
+    )?;
+    statement.bind((1, user_id.to_string().as_str()))?;
+    statement.bind((2, limit))?;
+
+    let mut quizzes = Vec::new();
+    while let State::Row = statement.next()? {
+        quizzes.push(QuizSummary {
+            quiz_id: statement.read::<i64, _>("quiz_id")?,
+            topic: statement
+                .read::<Option<String>, _>("topic")?
+                .unwrap_or_default(),
+            mode: statement.read::<String, _>("mode")?,
+            score: statement.read::<Option<f64>, _>("score")?.unwrap_or(0.0),
+            total_questions: statement
+                .read::<Option<i64>, _>("total_questions")?
+                .unwrap_or(0),
+            answered_questions: statement
+                .read::<Option<i64>, _>("answered_questions")?
+                .unwrap_or(0),
+            state: QuizState::from_key(&statement.read::<String, _>("state")?)
+                .unwrap_or(QuizState::Finished),
+            position: statement.read::<Option<i64>, _>("position")?,
+            resumable: statement.read::<i64, _>("resumable")? != 0,
+            taken_at: statement.read::<String, _>("taken_at")?,
+        });
+    }
+
+    Ok(quizzes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{CardKind, FlashCardData, QuizData, QuizManager};
+
+    fn quiz(user_id: u64, answers: &[(&str, &str)]) -> QuizManager {
+        let cards = answers
+            .iter()
+            .map(|(question, answer)| FlashCardData {
+                card_id: None,
+                difficulty: 1,
+                answer: answer.to_string(),
+                question: question.to_string(),
+                topic: String::from("chem"),
+                kind: CardKind::Text,
+            })
+            .collect();
+        let info = QuizData {
+            user_id,
+            topic: String::from("chem"),
+            ..QuizData::default()
+        };
+        let mut quiz = QuizManager::from_cards(info, cards);
+        quiz.is_new();
+        quiz
+    }
+
+    #[test]
+    fn replays_every_answer_of_a_quiz() {
+        let user_id = 3001;
+        let mut quiz = quiz(
+            user_id,
+            &[("H?", "1"), ("He?", "2"), ("Li?", "3"), ("Be?", "4")],
+        );
+        quiz.get_question().unwrap();
+        quiz.check_answer("1");
+        quiz.get_question().unwrap();
+        quiz.check_answer("5");
+        let missed = quiz.last_answer_id().unwrap();
+        quiz.get_question().unwrap();
+        quiz.skip();
+        quiz.get_question().unwrap();
+        quiz.time_out();
+        assert!(quiz.get_question().unwrap().is_none());
+        let quiz_id = quiz.get_quiz_id().unwrap();
+
+        assert!(load_report(quiz_id, user_id + 1).unwrap().is_none());
+        let report = load_report(quiz_id, user_id).unwrap().unwrap();
+        assert_eq!(report.answers.len(), 4);
+        let text = report.render();
+        assert!(
+            text.contains("Score: 1/2 · Skipped: 1 · Timed out: 1"),
+            "{}",
+            text
+        );
+        assert!(text.contains("✅ 1. H?\n   Your answer: 1\n"), "{}", text);
+        assert!(
+            text.contains("❌ 2. He?\n   Your answer: 5\n   ➡️ Correct answer: 2\n"),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("⏭️ 3. Li?\n   ➡️ Correct answer: 3\n"),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("⌛ 4. Be?\n   ➡️ Correct answer: 4\n"),
+            "{}",
+            text
+        );
+
+        let answer = load_answer(missed, user_id).unwrap().unwrap();
+        assert_eq!(answer.given_answer.as_deref(), Some("5"));
+        assert_eq!(answer.is_correct, Some(false));
+        assert!(load_answer(missed, user_id + 1).unwrap().is_none());
+    }
+
+    #[test]
+    fn lists_the_quizzes_newest_first() {
+        let user_id = 3002;
+        let mut finished = quiz(user_id, &[("H?", "1")]);
+        finished.get_question().unwrap();
+        finished.check_answer("1");
+        finished.get_question().unwrap();
+        let mut stopped = quiz(user_id, &[("H?", "1"), ("He?", "2")]);
+        stopped.get_question().unwrap();
+        stopped.save_quiz_result(QuizState::Stopped).unwrap();
+
+        let history = load_history(user_id, 10).unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].quiz_id, stopped.get_quiz_id().unwrap());
+        // generated questions are not stored, so the practice quiz cannot be resumed
+        assert!(history[0].render().ends_with("· stopped at question 1/2"));
+        assert!(history[1].render().ends_with("· completed, score 1/1"));
+        assert_eq!(load_history(user_id, 1).unwrap().len(), 1);
+    }
+}
@@ -1,6 +1,15 @@
-use crate::{constants, load_config, Actions};
-use serde_json;
+use crate::limits::{self, LimitError};
+use crate::llm::{
+    estimate_tokens, is_retryable, ChatMessage, GenerationParams, LlmProvider, StreamEvent,
+    CIRCUIT_BREAKER,
+};
+use crate::prompts::{self, Template};
+use crate::{cache, load_config, usage, Actions};
+use serde::de::DeserializeOwned;
+use serde_json::error::Category;
+use std::time::{Duration, Instant};
 use thiserror::Error;
+use tokio::sync::mpsc::UnboundedSender;
 
 #[derive(Debug, Error)]
 pub enum ExtractError {
@@ -8,6 +17,18 @@ pub enum ExtractError {
     MissingField,
     #[error("wrong type")]
     WrongType,
+    /// The reply is not JSON at all, or is cut short
+    #[error("invalid JSON: {source}")]
+    InvalidJson {
+        source: serde_json::Error,
+        content: String,
+    },
+    /// The reply is JSON, but not of the expected shape
+    #[error("unexpected JSON: {source}")]
+    UnexpectedShape {
+        source: serde_json::Error,
+        content: String,
+    },
 }
 
 #[derive(Debug, Error)]
@@ -16,14 +37,55 @@ pub enum RequestError {
     Http(#[from] reqwest::Error),
     #[error("json extraction failed: {0}")]
     Extract(#[from] ExtractError),
+    #[error("the AI is temporarily unavailable, please try again in a minute")]
+    Unavailable,
+    #[error(transparent)]
+    Limited(#[from] LimitError),
+}
+
+impl RequestError {
+    /// Whether the error comes from the service being down or overloaded, as opposed to a
+    /// request it will always refuse (400 too long, 401 bad key) or a malformed reply
+    pub fn is_transient(&self) -> bool {
+        match self {
+            RequestError::Http(e) => {
+                e.is_timeout() || e.is_connect() || e.status().is_some_and(is_retryable)
+            }
+            RequestError::Extract(_) | RequestError::Unavailable | RequestError::Limited(_) => {
+                false
+            }
+        }
+    }
 }
 
 pub struct ModelAnswer {
     pub id: String,
     pub content: String,
+    /// Tokens reported by the API, when it reports them
+    pub usage: Option<Usage>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+impl Usage {
+    /// Reads the `usage` block of a completion, or the `x_groq.usage` one of Groq streams
+    pub fn from_json(v: &serde_json::Value) -> Option<Usage> {
+        let usage = v
+            .get("usage")
+            .filter(|u| !u.is_null())
+            .or_else(|| v.get("x_groq").and_then(|x| x.get("usage")))?;
+        Some(Usage {
+            prompt_tokens: usage.get("prompt_tokens")?.as_u64()?,
+            completion_tokens: usage.get("completion_tokens")?.as_u64()?,
+        })
+    }
 }
 
-fn extract_answer(v: &serde_json::Value) -> Result<ModelAnswer, ExtractError> {
+pub fn extract_answer(v: &serde_json::Value) -> Result<ModelAnswer, ExtractError> {
     let id = v
         .get("id")
         .and_then(|x| x.as_str())
@@ -39,47 +101,339 @@ fn extract_answer(v: &serde_json::Value) -> Result<ModelAnswer, ExtractError> {
     Ok(ModelAnswer {
         id: id.to_string(),
         content: content.to_string(),
+        usage: Usage::from_json(v),
     })
 }
 
-pub async fn request(text: &str, a: Actions) -> Result<ModelAnswer, RequestError> {
-    let action: &str;
-    let config = load_config();
+/// Sends `text`, a prompt rendered from the templates of `prompts`, with the settings of `a`.
+/// The tokens used are accounted to `user_id`.
+pub async fn request(
+    text: &str,
+    a: Actions,
+    user_id: Option<u64>,
+) -> Result<ModelAnswer, RequestError> {
+    send(&[], text, a, user_id, false, None).await
+}
+
+/// Like `request`, continuing the conversation in `history`, and sending every piece of the
+/// answer to `on_delta` as it is generated, or a restart when a fallback model takes over.
+/// With `fresh`, a cached answer is not used.
+pub async fn request_streaming(
+    history: &[ChatMessage],
+    text: &str,
+    a: Actions,
+    user_id: Option<u64>,
+    fresh: bool,
+    on_delta: UnboundedSender<StreamEvent>,
+) -> Result<ModelAnswer, RequestError> {
+    send(history, text, a, user_id, fresh, Some(on_delta)).await
+}
+
+/// Like `request`, for a reply in JSON deserialized into `T`. The model is asked to follow
+/// `schema`, and asked once more with the parse error when its reply does not fit.
+pub async fn request_structured<T: DeserializeOwned>(
+    text: &str,
+    a: Actions,
+    user_id: Option<u64>,
+    schema: &serde_json::Value,
+) -> Result<T, RequestError> {
+    info!("{}", text);
+
+    let response_format = serde_json::json!({ "type": "json_object" });
+    let mut messages = vec![
+        ChatMessage::system(prompts::system()),
+        ChatMessage::user(format!(
+            "{}\n\n{}",
+            text,
+            prompts::render(Template::JsonSchema, &[("schema", &schema.to_string())])
+        )),
+    ];
+
+    let (answer, _) = dispatch(&messages, a, user_id, None, Some(&response_format)).await?;
+    match parse_structured(&answer.content) {
+        Ok(value) => Ok(value),
+        Err(e) => {
+            warn!(
+                "invalid structured reply for {}, asking again: {}",
+                a.key(),
+                e
+            );
+            messages.push(ChatMessage::assistant(answer.content));
+            messages.push(ChatMessage::user(prompts::render(
+                Template::JsonRetry,
+                &[("error", &e.to_string())],
+            )));
+            let (answer, _) = dispatch(&messages, a, user_id, None, Some(&response_format)).await?;
+            Ok(parse_structured(&answer.content)?)
+        }
+    }
+}
 
+/// Deserializes a JSON reply of the model, which may come wrapped in a markdown code block
+pub fn parse_structured<T: DeserializeOwned>(content: &str) -> Result<T, ExtractError> {
+    let json = content
+        .trim()
+        .trim_start_matches("```json")
+        .trim_start_matches("```")
+        .trim_end_matches("```");
+
+    serde_json::from_str(json).map_err(|source| {
+        let content = content.to_string();
+        match source.classify() {
+            Category::Data => ExtractError::UnexpectedShape { source, content },
+            _ => ExtractError::InvalidJson { source, content },
+        }
+    })
+}
+
+async fn send(
+    history: &[ChatMessage],
+    text: &str,
+    a: Actions,
+    user_id: Option<u64>,
+    fresh: bool,
+    on_delta: Option<UnboundedSender<StreamEvent>>,
+) -> Result<ModelAnswer, RequestError> {
     info!("{}", text);
 
-    match a {
-        Actions::Summarize => action = "Summarize the below text:",
-        Actions::Explain => action = "Explain the following concept:",
-        Actions::Define => {
-            action = "Provide a formal and short definition for the following concept:"
+    // answers are looked up for the model the action is routed to first, and answers depending
+    // on a conversation are never shared
+    let cacheable = a.is_cacheable() && history.is_empty();
+    if cacheable && !fresh {
+        let model = load_config().llm.route(a.key()).swap_remove(0).model;
+        match cache::lookup(a.key(), text, &model) {
+            Ok(Some(content)) => {
+                info!("cache hit for {} with {}", a.key(), model);
+                if let Some(tx) = &on_delta {
+                    let _ = tx.send(StreamEvent::Delta(content.clone()));
+                }
+                return Ok(ModelAnswer {
+                    id: String::from("cache"),
+                    content,
+                    usage: None,
+                });
+            }
+            Ok(None) => {}
+            Err(e) => warn!("failed to read the cache of {}: {}", a.key(), e),
         }
-        Actions::Translate => action = "Translate the provided text into the provided language",
-        Actions::Compare => {
-            action =
-                "Compare the following concepts, highlighting the similiraties and differences."
+    }
+
+    let mut messages = vec![ChatMessage::system(prompts::system())];
+    messages.extend_from_slice(history);
+    messages.push(ChatMessage::user(text));
+
+    let (answer, model) = dispatch(&messages, a, user_id, on_delta, None).await?;
+    // stored for the model that answered, a fallback's answer is only served while it is first
+    if cacheable {
+        if let Err(e) = cache::store(a.key(), text, &model, &answer.content) {
+            warn!("failed to cache the answer of {}: {}", a.key(), e);
         }
     }
+    Ok(answer)
+}
+
+// Tries the models routed to the action in order, until one answers, returning its answer and name
+async fn dispatch(
+    messages: &[ChatMessage],
+    a: Actions,
+    user_id: Option<u64>,
+    on_delta: Option<UnboundedSender<StreamEvent>>,
+    response_format: Option<&serde_json::Value>,
+) -> Result<(ModelAnswer, String), RequestError> {
+    let config = load_config();
+
+    // a command may need many requests, e.g. a long summary, so the quota is checked for each
+    if let Some(user_id) = user_id {
+        limits::check_quota(&config.limits, user_id)?;
+    }
 
-    let response: serde_json::Value = reqwest::Client::new()
-        .post(constants::LLM_API_URL)
-        .header("Authorization", format!("Bearer {}", config.llm_key))
-        .json(&serde_json::json!({
-          "messages": [
-            { "role": "system", "content": constants::SYSTEM_PROMPT },
-            { "role": "user", "content": format!("{}\n{}", action, text) }
-          ],
-          "model": constants::MODEL,
-          "temperature": 1,
-          "max_completion_tokens": 1024,
-          "top_p": 1,
-          "stream": false,
-          "stop": null
-        }))
-        .send()
-        .await?
-        .json()
-        .await?;
-
-    extract_answer(&response).map_err(RequestError::from)
+    if !CIRCUIT_BREAKER.lock().unwrap().allows_request() {
+        return Err(RequestError::Unavailable);
+    }
+
+    let provider = config.llm.provider(config.llm_key.as_deref());
+    let mut route = config.llm.route(a.key());
+    for params in &mut route {
+        params.response_format = response_format.cloned();
+    }
+
+    match first_answer(provider.as_ref(), route, messages, on_delta.as_ref()).await {
+        Ok((answer, model, latency)) => {
+            CIRCUIT_BREAKER.lock().unwrap().record_success();
+            // servers that do not report usage are accounted with an estimate
+            let tokens = answer.usage.unwrap_or_else(|| Usage {
+                prompt_tokens: messages
+                    .iter()
+                    .map(|m| estimate_tokens(&m.content) as u64)
+                    .sum(),
+                completion_tokens: estimate_tokens(&answer.content) as u64,
+            });
+            if let Err(e) = usage::record(user_id, a.key(), &model, tokens, latency) {
+                warn!("failed to record the usage of {}: {}", model, e);
+            }
+            Ok((answer, model))
+        }
+        Err((e, outage)) => {
+            // only an unreachable or overloaded service opens the circuit, not a request it refuses
+            if outage {
+                CIRCUIT_BREAKER.lock().unwrap().record_failure();
+            }
+            Err(e)
+        }
+    }
+}
+
+// Asks the models of `route` in order until one answers, returning the answer with the model
+// that gave it and how long it took. Otherwise returns the last error, and whether any model
+// failed because the service is down rather than refusing the request.
+async fn first_answer(
+    provider: &dyn LlmProvider,
+    route: Vec<GenerationParams>,
+    messages: &[ChatMessage],
+    on_delta: Option<&UnboundedSender<StreamEvent>>,
+) -> Result<(ModelAnswer, String, Duration), (RequestError, bool)> {
+    let mut last_error = None;
+    let mut outage = false;
+    for params in route {
+        let started = Instant::now();
+        let result = match on_delta {
+            Some(tx) => {
+                // a model failing mid-answer leaves a partial answer behind
+                if last_error.is_some() {
+                    let _ = tx.send(StreamEvent::Restart);
+                }
+                provider.stream(messages, &params, tx.clone()).await
+            }
+            None => provider.complete(messages, &params).await,
+        };
+        match result {
+            Ok(answer) => return Ok((answer, params.model, started.elapsed())),
+            Err(e) => {
+                warn!("model {} failed: {}", params.model, e);
+                outage |= e.is_transient();
+                last_error = Some(e);
+            }
+        }
+    }
+
+    // the route always holds at least the default model
+    Err((last_error.unwrap(), outage))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::{LlmFuture, MockProvider};
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Reply {
+        answer: String,
+    }
+
+    #[test]
+    fn parses_plain_and_fenced_json() {
+        let expected = Reply {
+            answer: String::from("42"),
+        };
+        assert_eq!(
+            parse_structured::<Reply>(r#" {"answer": "42"} "#).unwrap(),
+            expected
+        );
+        assert_eq!(
+            parse_structured::<Reply>("```json\n{\"answer\": \"42\"}\n```").unwrap(),
+            expected
+        );
+        assert_eq!(
+            parse_structured::<Reply>("```\n{\"answer\": \"42\"}\n```").unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn tells_invalid_json_from_an_unexpected_shape() {
+        assert!(matches!(
+            parse_structured::<Reply>(r#"{"answer": "4"#),
+            Err(ExtractError::InvalidJson { .. })
+        ));
+        assert!(matches!(
+            parse_structured::<Reply>("Sure! Here is the JSON"),
+            Err(ExtractError::InvalidJson { .. })
+        ));
+        assert!(matches!(
+            parse_structured::<Reply>(r#"{"answer": 42}"#),
+            Err(ExtractError::UnexpectedShape { content, .. }) if content == r#"{"answer": 42}"#
+        ));
+    }
+
+    // The mock provider, except for the model "down" which always fails
+    struct PartlyDown(MockProvider);
+
+    impl LlmProvider for PartlyDown {
+        fn complete<'a>(
+            &'a self,
+            messages: &'a [ChatMessage],
+            params: &'a GenerationParams,
+        ) -> LlmFuture<'a> {
+            if params.model == "down" {
+                return Box::pin(async { Err(RequestError::Unavailable) });
+            }
+            self.0.complete(messages, params)
+        }
+    }
+
+    fn route(models: &[&str]) -> Vec<GenerationParams> {
+        models
+            .iter()
+            .map(|model| GenerationParams {
+                model: model.to_string(),
+                temperature: 1.0,
+                max_tokens: 1024,
+                response_format: None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_next_model() {
+        let provider = PartlyDown(MockProvider { reply: None });
+        let messages = [ChatMessage::user("hello")];
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+
+        let Ok((answer, model, _)) = first_answer(
+            &provider,
+            route(&["down", "down", "up"]),
+            &messages,
+            Some(&tx),
+        )
+        .await
+        else {
+            panic!("the fallback model did not answer");
+        };
+        assert_eq!(answer.content, "[mock] hello");
+        assert_eq!(model, "up");
+
+        drop(tx);
+        let mut events = Vec::new();
+        while let Some(event) = rx.recv().await {
+            events.push(event);
+        }
+        assert_eq!(
+            events,
+            vec![
+                StreamEvent::Restart,
+                StreamEvent::Restart,
+                StreamEvent::Delta(String::from("[mock] hello")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn returns_the_last_error_when_every_model_fails() {
+        let provider = PartlyDown(MockProvider { reply: None });
+        let messages = [ChatMessage::user("hello")];
+
+        let result = first_answer(&provider, route(&["down", "down"]), &messages, None).await;
+        assert!(matches!(result, Err((RequestError::Unavailable, false))));
+    }
 }
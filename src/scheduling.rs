@@ -0,0 +1,182 @@
+use crate::db;
+
+/// Self-reported recall quality, as offered by the buttons of the recall mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rating {
+    Again,
+    Hard,
+    Good,
+    Easy,
+}
+
+impl Rating {
+    pub const ALL: [Rating; 4] = [Rating::Again, Rating::Hard, Rating::Good, Rating::Easy];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Rating::Again => "Again",
+            Rating::Hard => "Hard",
+            Rating::Good => "Good",
+            Rating::Easy => "Easy",
+        }
+    }
+
+    pub fn key(self) -> &'static str {
+        match self {
+            Rating::Again => "again",
+            Rating::Hard => "hard",
+            Rating::Good => "good",
+            Rating::Easy => "easy",
+        }
+    }
+
+    pub fn from_key(key: &str) -> Option<Rating> {
+        Rating::ALL.into_iter().find(|r| r.key() == key)
+    }
+
+    /// Share of a point this rating is worth in the quiz score
+    pub fn credit(self) -> f64 {
+        match self {
+            Rating::Again => 0.0,
+            Rating::Hard => 0.5,
+            Rating::Good | Rating::Easy => 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ScheduleState {
+    pub ease: f64,
+    pub interval_days: i64,
+    pub repetitions: i64,
+}
+
+const MIN_EASE: f64 = 1.3;
+
+/// SM-2 style update of a card's scheduling state after a review
+pub fn next_state(state: ScheduleState, rating: Rating) -> ScheduleState {
+    match rating {
+        Rating::Again => ScheduleState {
+            ease: (state.ease - 0.2).max(MIN_EASE),
+            interval_days: 0,
+            repetitions: 0,
+        },
+        Rating::Hard => ScheduleState {
+            ease: (state.ease - 0.15).max(MIN_EASE),
+            interval_days: ((state.interval_days as f64 * 1.2).round() as i64).max(1),
+            repetitions: state.repetitions + 1,
+        },
+        Rating::Good | Rating::Easy => {
+            let interval = match state.repetitions {
+                0 => 1.0,
+                1 => 6.0,
+                _ => state.interval_days as f64 * state.ease,
+            };
+            let (ease, bonus) = if rating == Rating::Easy {
+                (state.ease + 0.15, 1.3)
+            } else {
+                (state.ease, 1.0)
+            };
+            ScheduleState {
+                ease,
+                interval_days: (interval * bonus).round() as i64,
+                repetitions: state.repetitions + 1,
+            }
+        }
+    }
+}
+
+/// Applies a review to the stored scheduling state of a card and moves its due date
+pub fn record_review(card_id: i64, rating: Rating) -> Result<(), sqlite::Error> {
+    let db = db::get_db();
+
+    let state = {
+        let mut statement = db.prepare(
+            "SELECT ease_factor, interval_days, repetitions FROM flashcards WHERE card_id = ?",
+        )?;
+        statement.bind((1, card_id))?;
+        if let sqlite::State::Done = statement.next()? {
+            return Ok(());
+        }
+        ScheduleState {
+            ease: statement.read::<f64, _>("ease_factor")?,
+            interval_days: statement.read::<i64, _>("interval_days")?,
+            repetitions: statement.read::<i64, _>("repetitions")?,
+        }
+    };
+
+    let next = next_state(state, rating);
+
+    let mut statement = db.prepare(
+        "
+        UPDATE flashcards
+        SET ease_factor = ?,
+            interval_days = ?,
+            repetitions = ?,
+            last_quiz_time = CURRENT_TIMESTAMP,
+            due_at = datetime('now', '+' || ? || ' days')
+        WHERE card_id = ?",
+    )?;
+    statement.bind((1, next.ease))?;
+    statement.bind((2, next.interval_days))?;
+    statement.bind((3, next.repetitions))?;
+    statement.bind((4, next.interval_days))?;
+    statement.bind((5, card_id))?;
+    statement.next()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NEW: ScheduleState = ScheduleState {
+        ease: 2.5,
+        interval_days: 0,
+        repetitions: 0,
+    };
+
+    fn review(ratings: &[Rating]) -> ScheduleState {
+        ratings.iter().fold(NEW, |state, &r| next_state(state, r))
+    }
+
+    #[test]
+    fn grows_intervals_on_good_answers() {
+        let intervals: Vec<i64> = (1..=4)
+            .map(|n| review(&vec![Rating::Good; n]).interval_days)
+            .collect();
+        assert_eq!(intervals, vec![1, 6, 15, 38]);
+        assert_eq!(review(&[Rating::Good; 4]).ease, 2.5);
+    }
+
+    #[test]
+    fn rewards_easy_answers() {
+        let state = review(&[Rating::Easy, Rating::Easy]);
+        assert_eq!(state.interval_days, 8);
+        assert!((state.ease - 2.8).abs() < 1e-9);
+    }
+
+    #[test]
+    fn starts_over_after_a_miss() {
+        let state = review(&[Rating::Good, Rating::Good, Rating::Again]);
+        assert_eq!(state.interval_days, 0);
+        assert_eq!(state.repetitions, 0);
+        assert!((state.ease - 2.3).abs() < 1e-9);
+        assert_eq!(next_state(state, Rating::Good).interval_days, 1);
+    }
+
+    #[test]
+    fn barely_grows_hard_intervals() {
+        let state = review(&[Rating::Good, Rating::Good, Rating::Hard]);
+        assert_eq!(state.interval_days, 7);
+        assert_eq!(review(&[Rating::Hard]).interval_days, 1);
+    }
+
+    #[test]
+    fn keeps_a_minimum_ease() {
+        let state = review(&[Rating::Again; 20]);
+        assert_eq!(state.ease, MIN_EASE);
+        assert_eq!(review(&[Rating::Hard; 20]).ease, MIN_EASE);
+    }
+}
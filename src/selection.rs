@@ -0,0 +1,361 @@
+use crate::db;
+use crate::types::{CardKind, FlashCardData, QuizMode};
+
+use rand::rngs::StdRng;
+use rand::seq::{IndexedRandom, SliceRandom};
+use rand::{Rng, SeedableRng};
+use sqlite::State;
+use std::collections::HashMap;
+
+/// What the quiz history says about a card
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CardStats {
+    /// Share of wrong answers among the most recent ones, None if never answered
+    pub recent_error_rate: Option<f64>,
+    /// Days elapsed since the card was last reviewed, None if it never was
+    pub days_since_review: Option<f64>,
+}
+
+/// Decides in which order the cards of a quiz are asked
+pub trait SelectionStrategy {
+    /// Orders the cards of a new quiz
+    fn order(
+        &self,
+        cards: Vec<FlashCardData>,
+        stats: &HashMap<i64, CardStats>,
+    ) -> Vec<FlashCardData>;
+
+    /// How many questions later a missed card is asked again, None to never repeat it
+    fn reinsert_gap(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// The original ordering: easiest cards first
+pub struct DifficultyOrder;
+
+impl SelectionStrategy for DifficultyOrder {
+    fn order(
+        &self,
+        mut cards: Vec<FlashCardData>,
+        _stats: &HashMap<i64, CardStats>,
+    ) -> Vec<FlashCardData> {
+        cards.sort_by_key(|c| c.difficulty);
+        cards
+    }
+}
+
+/// Weak cards first, and missed cards come back a few questions later
+pub struct AdaptiveOrder {
+    pub reinsert_gap: usize,
+}
+
+// Prior for cards that were never answered, so new cards are neither buried nor favoured
+const UNSEEN_ERROR_RATE: f64 = 0.5;
+// Past this many days without a review, a card is considered fully forgotten
+const MAX_STALENESS_DAYS: f64 = 30.0;
+
+impl AdaptiveOrder {
+    /// Higher weights are asked first
+    pub fn weight(card: &FlashCardData, stats: &CardStats) -> f64 {
+        let error_rate = stats.recent_error_rate.unwrap_or(UNSEEN_ERROR_RATE);
+        let staleness = stats
+            .days_since_review
+            .unwrap_or(MAX_STALENESS_DAYS)
+            .min(MAX_STALENESS_DAYS)
+            / MAX_STALENESS_DAYS;
+        let difficulty = card.difficulty.clamp(0, 10) as f64 / 10.0;
+
+        3.0 * error_rate + 2.0 * staleness + difficulty
+    }
+}
+
+impl SelectionStrategy for AdaptiveOrder {
+    fn order(
+        &self,
+        cards: Vec<FlashCardData>,
+        stats: &HashMap<i64, CardStats>,
+    ) -> Vec<FlashCardData> {
+        let mut weighted: Vec<(f64, FlashCardData)> = cards
+            .into_iter()
+            .map(|c| {
+                let s = c
+                    .card_id
+                    .and_then(|id| stats.get(&id))
+                    .copied()
+                    .unwrap_or_default();
+                (Self::weight(&c, &s), c)
+            })
+            .collect();
+
+        weighted.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+        weighted.into_iter().map(|(_, c)| c).collect()
+    }
+
+    fn reinsert_gap(&self) -> Option<usize> {
+        Some(self.reinsert_gap)
+    }
+}
+
+/// Strategies selectable with `--order`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OrderKind {
+    #[default]
+    Difficulty,
+    Adaptive,
+}
+
+impl OrderKind {
+    pub fn key(self) -> &'static str {
+        match self {
+            OrderKind::Difficulty => "difficulty",
+            OrderKind::Adaptive => "adaptive",
+        }
+    }
+
+    pub fn from_key(key: &str) -> Option<OrderKind> {
+        match key {
+            "difficulty" => Some(OrderKind::Difficulty),
+            "adaptive" => Some(OrderKind::Adaptive),
+            _ => None,
+        }
+    }
+
+    pub fn strategy(self) -> Box<dyn SelectionStrategy> {
+        match self {
+            OrderKind::Difficulty => Box::new(DifficultyOrder),
+            OrderKind::Adaptive => Box::new(AdaptiveOrder { reinsert_gap: 3 }),
+        }
+    }
+}
+
+// Number of most recent answers the error rate of a card is computed on
+const RECENT_ANSWERS: usize = 5;
+
+/// Collects the recent error rate and the time since the last review of every card of a user
+pub fn load_stats(user_id: u64) -> Result<HashMap<i64, CardStats>, sqlite::Error> {
+    let db = db::get_db();
+    let mut stats: HashMap<i64, CardStats> = HashMap::new();
+
+    let mut statement = db.prepare(
+        "
+        SELECT card_id, julianday('now') - julianday(last_quiz_time) AS days
+        FROM flashcards
+        WHERE user_id = ? AND last_quiz_time IS NOT NULL",
+    )?;
+    statement.bind((1, user_id.to_string().as_str()))?;
+    while let State::Row = statement.next()? {
+        stats
+            .entry(statement.read::<i64, _>("card_id")?)
+            .or_default()
+            .days_since_review = statement.read::<Option<f64>, _>("days")?;
+    }
+
+    // a missed true/false statement may be a generated one, so it says little about the card,
+    // and skipped or timed out questions were not answered at all
+    let mut answers: HashMap<i64, Vec<bool>> = HashMap::new();
+    let mut statement = db.prepare(
+        "
+        SELECT quiz_answers.card_id, quiz_answers.is_correct
+        FROM quiz_answers
+        JOIN quiz_reports ON quiz_reports.quiz_id = quiz_answers.quiz_id
+        WHERE quiz_reports.user_id = ? AND quiz_reports.mode <> ?
+            AND quiz_answers.card_id IS NOT NULL AND quiz_answers.skipped = 0
+            AND quiz_answers.is_correct IS NOT NULL
+        ORDER BY quiz_answers.answer_id DESC",
+    )?;
+    statement.bind((1, user_id.to_string().as_str()))?;
+    statement.bind((2, QuizMode::TrueFalse.key()))?;
+    while let State::Row = statement.next()? {
+        let recent = answers
+            .entry(statement.read::<i64, _>("card_id")?)
+            .or_default();
+        if recent.len() < RECENT_ANSWERS {
+            recent.push(statement.read::<i64, _>("is_correct")? != 0);
+        }
+    }
+
+    for (card_id, recent) in answers {
+        let wrong = recent.iter().filter(|correct| !**correct).count();
+        stats.entry(card_id).or_default().recent_error_rate =
+            Some(wrong as f64 / recent.len() as f64);
+    }
+
+    Ok(stats)
+}
+
+/// Draws up to `n` distinct cards at random, harder ones (by `difficulty`) being more likely
+pub fn sample_by_difficulty<R: Rng + ?Sized>(
+    cards: Vec<FlashCardData>,
+    n: usize,
+    rng: &mut R,
+) -> Vec<FlashCardData> {
+    let n = n.min(cards.len());
+
+    match cards.choose_multiple_weighted(rng, n, |c| c.difficulty.max(1) as f64) {
+        Ok(chosen) => chosen.cloned().collect(),
+        Err(e) => {
+            warn!(
+                "weighted sampling failed, falling back to the first cards: {}",
+                e
+            );
+            cards.into_iter().take(n).collect()
+        }
+    }
+}
+
+/// Shuffles the cards, always in the same order for a given seed
+pub fn shuffle(mut cards: Vec<FlashCardData>, seed: Option<u64>) -> Vec<FlashCardData> {
+    let mut rng = match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_rng(&mut rand::rng()),
+    };
+    cards.shuffle(&mut rng);
+    cards
+}
+
+/// Turns every card into a statement to judge as true or false: the card with its own
+/// answer, or about half of the time with the answer of another card of the same topic
+pub fn true_false_statements<R: Rng + ?Sized>(
+    cards: Vec<FlashCardData>,
+    pool: &[FlashCardData],
+    rng: &mut R,
+) -> Vec<FlashCardData> {
+    cards
+        .into_iter()
+        .map(|card| {
+            let decoys: Vec<&FlashCardData> = pool
+                .iter()
+                .filter(|c| c.topic == card.topic && c.answer.trim() != card.answer.trim())
+                .collect();
+
+            let shown = match decoys.choose(rng) {
+                Some(decoy) if rng.random_bool(0.5) => decoy.answer.clone(),
+                _ => card.answer.clone(),
+            };
+            let is_true = shown == card.answer;
+
+            FlashCardData {
+                question: format!("{}\n➡️ {}", card.question, shown),
+                answer: String::from(if is_true { "True" } else { "False" }),
+                kind: CardKind::Choice(vec![String::from("True"), String::from("False")]),
+                ..card
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{QuizData, QuizManager};
+
+    fn card(card_id: i64, difficulty: i64) -> FlashCardData {
+        FlashCardData {
+            card_id: Some(card_id),
+            difficulty,
+            answer: String::from("answer"),
+            question: format!("question {}", card_id),
+            topic: String::from("bio"),
+            kind: CardKind::Text,
+        }
+    }
+
+    fn stored_card(user_id: u64, question: &str, answer: &str) -> FlashCardData {
+        let db = db::get_db();
+        let mut statement = db
+            .prepare(format!(
+                "INSERT INTO flashcards (user_id, question, answer, topic, difficulty)
+                VALUES (?, ?, ?, 'bio', 1)
+                RETURNING {}",
+                FlashCardData::COLUMNS
+            ))
+            .unwrap();
+        statement.bind((1, user_id.to_string().as_str())).unwrap();
+        statement.bind((2, question)).unwrap();
+        statement.bind((3, answer)).unwrap();
+        statement.next().unwrap();
+        FlashCardData::read(&statement).unwrap()
+    }
+
+    fn answer_all(user_id: u64, mode: QuizMode, cards: Vec<FlashCardData>, answers: &[&str]) {
+        let info = QuizData {
+            user_id,
+            topic: String::from("bio"),
+            mode,
+            ..QuizData::default()
+        };
+        let mut quiz = QuizManager::from_cards(info, cards);
+        quiz.is_new();
+        for answer in answers {
+            quiz.get_question().unwrap();
+            match *answer {
+                "/skip" => {
+                    quiz.skip();
+                }
+                answer => {
+                    quiz.check_answer(answer);
+                }
+            }
+        }
+    }
+
+    fn ids(cards: &[FlashCardData]) -> Vec<i64> {
+        cards.iter().filter_map(|c| c.card_id).collect()
+    }
+
+    #[test]
+    fn asks_weak_and_forgotten_cards_first() {
+        let stats = HashMap::from([
+            (
+                1,
+                CardStats {
+                    recent_error_rate: Some(1.0),
+                    days_since_review: Some(40.0),
+                },
+            ),
+            (
+                2,
+                CardStats {
+                    recent_error_rate: Some(0.0),
+                    days_since_review: Some(0.0),
+                },
+            ),
+        ]);
+        let cards = vec![card(2, 9), card(3, 0), card(1, 5)];
+
+        let adaptive = OrderKind::Adaptive.strategy();
+        assert_eq!(ids(&adaptive.order(cards.clone(), &stats)), [1, 3, 2]);
+        assert_eq!(adaptive.reinsert_gap(), Some(3));
+        let by_difficulty = OrderKind::Difficulty.strategy();
+        assert_eq!(ids(&by_difficulty.order(cards, &stats)), [3, 1, 2]);
+        assert_eq!(by_difficulty.reinsert_gap(), None);
+    }
+
+    #[test]
+    fn computes_stats_from_the_answered_questions() {
+        let user_id = 3401;
+        let cell = stored_card(user_id, "Unit of life?", "cell");
+        let atp = stored_card(user_id, "Energy carrier?", "ATP");
+        let skipped = stored_card(user_id, "Powerhouse?", "mitochondria");
+
+        answer_all(
+            user_id,
+            QuizMode::Typed,
+            vec![cell.clone(), atp.clone()],
+            &["tissue", "ATP"],
+        );
+        answer_all(user_id, QuizMode::Typed, vec![cell.clone()], &["cell"]);
+        // a missed true/false statement and a skipped question tell nothing about the card
+        answer_all(user_id, QuizMode::TrueFalse, vec![atp.clone()], &["False"]);
+        answer_all(user_id, QuizMode::Typed, vec![skipped.clone()], &["/skip"]);
+
+        let stats = load_stats(user_id).unwrap();
+        let cell = stats[&cell.card_id.unwrap()];
+        assert_eq!(cell.recent_error_rate, Some(0.5));
+        assert!(cell.days_since_review.is_some_and(|days| days < 0.01));
+        assert_eq!(stats[&atp.card_id.unwrap()].recent_error_rate, Some(0.0));
+        assert!(!stats.contains_key(&skipped.card_id.unwrap()));
+        assert!(load_stats(user_id + 1).unwrap().is_empty());
+    }
+}
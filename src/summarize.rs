@@ -0,0 +1,128 @@
+use crate::llm::estimate_tokens;
+use crate::prompts::{self, Template};
+use crate::requests::{self, RequestError};
+use crate::{constants, render, Actions};
+
+/// Output formats of `/summarize --format`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SummaryFormat {
+    Tldr,
+    Bullets,
+    Outline,
+    StudyNotes,
+}
+
+impl SummaryFormat {
+    pub const ALL: [SummaryFormat; 4] = [
+        SummaryFormat::Tldr,
+        SummaryFormat::Bullets,
+        SummaryFormat::Outline,
+        SummaryFormat::StudyNotes,
+    ];
+
+    pub fn key(self) -> &'static str {
+        match self {
+            SummaryFormat::Tldr => "tldr",
+            SummaryFormat::Bullets => "bullets",
+            SummaryFormat::Outline => "outline",
+            SummaryFormat::StudyNotes => "study-notes",
+        }
+    }
+
+    pub fn from_key(key: &str) -> Option<SummaryFormat> {
+        SummaryFormat::ALL.into_iter().find(|f| f.key() == key)
+    }
+
+    /// Added to the prompt of the final summary
+    pub fn instruction(self) -> String {
+        let template = match self {
+            SummaryFormat::Tldr => Template::FormatTldr,
+            SummaryFormat::Bullets => Template::FormatBullets,
+            SummaryFormat::Outline => Template::FormatOutline,
+            SummaryFormat::StudyNotes => Template::FormatStudyNotes,
+        };
+        prompts::render(template, &[])
+    }
+}
+
+/// Number of pieces a text is summarized in before the final summary, 1 if it fits at once
+pub fn chunk_count(text: &str) -> usize {
+    chunks(text).len()
+}
+
+fn chunks(text: &str) -> Vec<String> {
+    render::split_text(text, constants::SUMMARY_CHUNK_TOKENS * 4, false)
+}
+
+/// Shrinks a text too long for a single request: every chunk is summarized, then the
+/// summaries are joined, and summarized again as long as they are still too long
+pub async fn condense(text: &str, user_id: Option<u64>) -> Result<String, RequestError> {
+    let mut text = text.to_string();
+
+    while estimate_tokens(&text) > constants::SUMMARY_CHUNK_TOKENS {
+        let mut summaries = Vec::new();
+        for chunk in chunks(&text) {
+            let answer = requests::request(
+                &prompts::render(Template::SummarizeChunk, &[("text", &chunk)]),
+                Actions::Summarize,
+                user_id,
+            )
+            .await?;
+            summaries.push(answer.content);
+        }
+        let joined = summaries.join("\n\n");
+        // a model that does not shorten the text would loop forever
+        if joined.chars().count() >= text.chars().count() {
+            return Ok(joined);
+        }
+        text = joined;
+    }
+
+    Ok(text)
+}
+
+/// Prompt of the final summary request, asking for `format` when one was chosen
+pub fn final_prompt(text: &str, format: Option<SummaryFormat>) -> String {
+    let instruction = format.map(SummaryFormat::instruction).unwrap_or_default();
+    Actions::Summarize.prompt(&[("text", text), ("format", &instruction)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarizes_a_short_text_at_once() {
+        assert_eq!(chunk_count("A short text."), 1);
+        assert_eq!(chunks("  A short text.\n"), ["A short text."]);
+    }
+
+    #[test]
+    fn splits_a_long_text_between_paragraphs() {
+        let paragraph = format!("{}.", "A sentence of the paper ".repeat(40).trim());
+        let text = vec![paragraph.as_str(); 40].join("\n\n");
+        let chunks = chunks(&text);
+
+        assert_eq!(chunk_count(&text), chunks.len());
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert!(estimate_tokens(chunk) <= constants::SUMMARY_CHUNK_TOKENS);
+            assert!(chunk.starts_with("A sentence") && chunk.ends_with("paper."));
+        }
+        let paragraphs: usize = chunks.iter().map(|c| c.split("\n\n").count()).sum();
+        assert_eq!(paragraphs, 40);
+    }
+
+    #[test]
+    fn asks_for_the_chosen_format() {
+        for format in SummaryFormat::ALL {
+            assert_eq!(SummaryFormat::from_key(format.key()), Some(format));
+        }
+        let notes = final_prompt("Mitosis.", Some(SummaryFormat::StudyNotes));
+        assert!(notes.contains("study notes") && notes.ends_with("\nMitosis."));
+        assert_eq!(
+            final_prompt("Mitosis.", None),
+            "Summarize the below text. \nMitosis."
+        );
+    }
+}
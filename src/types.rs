@@ -1,150 +1,1171 @@
-use crate::db;
+use crate::scheduling::{self, Rating};
+use crate::selection::{self, OrderKind};
+use crate::{constants, db, grading};
 
-use sqlite::State;
+use sqlite::{State, Statement};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy)]
+pub enum Tolerance {
+    Relative(f64),
+    Absolute(f64),
+}
+
+impl fmt::Display for Tolerance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Tolerance::Relative(r) => write!(f, "±{}%", r * 100.0),
+            Tolerance::Absolute(a) => write!(f, "±{}", a),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NumericSpec {
+    pub value: f64,
+    pub tolerance: Tolerance,
+    pub unit: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum CardKind {
+    Text,
+    Numeric(NumericSpec),
+    /// One of the listed options is the answer; it can be given by its letter or its text
+    Choice(Vec<String>),
+}
+
+impl CardKind {
+    /// The options of a choice card, one per line, as stored in the `choices` columns
+    pub fn choices(&self) -> Option<String> {
+        match self {
+            CardKind::Choice(options) => Some(options.join("\n")),
+            _ => None,
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct FlashCardData {
+    /// None for cards generated on the fly, which are not stored
+    pub card_id: Option<i64>,
     pub difficulty: i64,
     pub answer: String,
     pub question: String,
+    pub topic: String,
+    pub kind: CardKind,
+}
+
+impl FlashCardData {
+    /// Columns expected by `FlashCardData::read`, to be used in SELECT statements
+    pub const COLUMNS: &'static str =
+        "card_id, question, answer, topic, difficulty, card_type, numeric_value, tolerance, tolerance_kind, unit, choices";
+
+    pub fn read(statement: &Statement) -> Result<Self, sqlite::Error> {
+        let kind = match statement.read::<String, _>("card_type")?.as_str() {
+            "numeric" => {
+                let tolerance = statement.read::<f64, _>("tolerance")?;
+                CardKind::Numeric(NumericSpec {
+                    value: statement.read::<f64, _>("numeric_value")?,
+                    tolerance: match statement.read::<String, _>("tolerance_kind")?.as_str() {
+                        "relative" => Tolerance::Relative(tolerance),
+                        _ => Tolerance::Absolute(tolerance),
+                    },
+                    unit: statement.read::<Option<String>, _>("unit")?,
+                })
+            }
+            "choice" => CardKind::Choice(
+                statement
+                    .read::<Option<String>, _>("choices")?
+                    .unwrap_or_default()
+                    .lines()
+                    .map(String::from)
+                    .collect(),
+            ),
+            _ => CardKind::Text,
+        };
+
+        Ok(FlashCardData {
+            card_id: Some(statement.read::<i64, _>("card_id")?),
+            question: statement.read::<String, _>("question")?,
+            answer: statement.read::<String, _>("answer")?,
+            topic: statement.read::<String, _>("topic")?,
+            difficulty: statement.read::<i64, _>("difficulty")?,
+            kind,
+        })
+    }
+
+    pub fn is_correct(&self, input: &str) -> bool {
+        match &self.kind {
+            CardKind::Text => input.trim().to_lowercase() == self.answer.trim().to_lowercase(),
+            CardKind::Numeric(spec) => grading::check_numeric(input, spec),
+            CardKind::Choice(options) => {
+                let input = input.trim();
+                // "T" and "F" are not option letters of a true/false question, but initials
+                let by_initial = |letter: u8| {
+                    let mut matching = options.iter().filter(|o| {
+                        o.as_bytes().first().map(u8::to_ascii_uppercase) == Some(letter)
+                    });
+                    match (matching.next(), matching.next()) {
+                        (Some(option), None) => Some(option.as_str()),
+                        _ => None,
+                    }
+                };
+                let chosen = match input.to_ascii_uppercase().as_bytes() {
+                    [letter @ b'A'..=b'Z'] => options
+                        .get((letter - b'A') as usize)
+                        .map(String::as_str)
+                        .or_else(|| by_initial(*letter))
+                        .unwrap_or(input),
+                    _ => input,
+                };
+                chosen.eq_ignore_ascii_case(self.answer.trim())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum QuizMode {
+    /// The user types the answer and the bot grades it
+    #[default]
+    Typed,
+    /// The user reveals the answer and rates their own recall
+    Recall,
+    /// Typed answers graded only at the end, like a real test
+    Exam,
+    /// Each card becomes a statement, right or mismatched, judged with two buttons
+    TrueFalse,
+}
+
+impl QuizMode {
+    pub fn key(self) -> &'static str {
+        match self {
+            QuizMode::Typed => "typed",
+            QuizMode::Recall => "recall",
+            QuizMode::Exam => "exam",
+            QuizMode::TrueFalse => "truefalse",
+        }
+    }
+
+    pub fn from_key(key: &str) -> Option<QuizMode> {
+        [
+            QuizMode::Typed,
+            QuizMode::Recall,
+            QuizMode::Exam,
+            QuizMode::TrueFalse,
+        ]
+        .into_iter()
+        .find(|m| m.key() == key)
+    }
+}
+
+/// Where a quiz stands, as stored in `quiz_reports.state`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuizState {
+    /// Being taken in a chat
+    Running,
+    /// Stopped with /stop or by a restart of the bot, and resumable
+    Stopped,
+    Finished,
+}
+
+impl QuizState {
+    pub fn key(self) -> &'static str {
+        match self {
+            QuizState::Running => "running",
+            QuizState::Stopped => "stopped",
+            QuizState::Finished => "finished",
+        }
+    }
+
+    pub fn from_key(key: &str) -> Option<QuizState> {
+        [QuizState::Running, QuizState::Stopped, QuizState::Finished]
+            .into_iter()
+            .find(|s| s.key() == key)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TimeLimit {
+    #[default]
+    None,
+    /// Every question has to be answered within the given time
+    PerQuestion(Duration),
+    /// The whole quiz ends when the given time is over
+    Sprint(Duration),
+}
+
+impl TimeLimit {
+    /// Kind of limit as stored in `quiz_reports.time_limit_kind`
+    pub fn kind(self) -> &'static str {
+        match self {
+            TimeLimit::None => "none",
+            TimeLimit::PerQuestion(_) => "question",
+            TimeLimit::Sprint(_) => "sprint",
+        }
+    }
+
+    pub fn duration(self) -> Option<Duration> {
+        match self {
+            TimeLimit::None => None,
+            TimeLimit::PerQuestion(limit) | TimeLimit::Sprint(limit) => Some(limit),
+        }
+    }
+
+    pub fn from_kind(kind: &str, duration: Option<Duration>) -> TimeLimit {
+        match (kind, duration) {
+            ("question", Some(limit)) => TimeLimit::PerQuestion(limit),
+            ("sprint", Some(limit)) => TimeLimit::Sprint(limit),
+            _ => TimeLimit::None,
+        }
+    }
 }
 
 #[derive(Default, Clone)]
 pub struct QuizData {
     pub user_id: u64,
     pub topic: String,
+    pub mode: QuizMode,
+    pub time_limit: TimeLimit,
+    /// Number of cards to draw from the topic, all of them when unset
+    pub question_count: Option<usize>,
+    pub order: OrderKind,
+    /// Asks the cards in random order, reproducible when a seed is given
+    pub shuffle: bool,
+    pub seed: Option<u64>,
+}
+
+impl QuizData {
+    /// Topics the quiz draws from, None meaning every topic of the user.
+    /// `topic` is either "all" or a comma separated list such as "bio,chem".
+    pub fn topics(&self) -> Option<Vec<&str>> {
+        if self.topic.trim().eq_ignore_ascii_case("all") {
+            return None;
+        }
+        Some(
+            self.topic
+                .split(',')
+                .map(str::trim)
+                .filter(|t| !t.is_empty())
+                .collect(),
+        )
+    }
 }
 
 #[derive(Default, Clone)]
 pub struct QuizManager {
     info: QuizData,
     current: usize,
-    score: usize,
+    score: f64,
     total_questions: usize,
     answered_questions: usize,
+    skipped_questions: usize,
     cards: Vec<FlashCardData>,
     new: bool,
+    revealed: bool,
+    ratings: [usize; 4],
+    hints_used: usize,
+    quiz_id: Option<i64>,
+    asked_at: Option<Instant>,
+    last_answer_id: Option<i64>,
+    timed_out_questions: usize,
+    total_response_time: Duration,
+    started_at: Option<Instant>,
+    /// Time of a sprint spent before it was stopped and resumed
+    sprint_spent: Duration,
 }
 
+/// Number of hints available per question, the last one being generated by the model
+pub const MAX_HINTS: usize = 3;
+
+// Times a missed card can come back in an adaptive quiz
+const MAX_REPEATS: usize = 3;
+
+// Share of a question's credit lost with every hint
+const HINT_PENALTY: f64 = 0.25;
+
 impl QuizManager {
     pub fn new(info: QuizData) -> Self {
         let cards: Vec<FlashCardData> = {
+            let topics = info.topics();
+            let topic_filter = match &topics {
+                Some(topics) => format!("AND topic IN ({})", vec!["?"; topics.len()].join(", ")),
+                None => String::new(),
+            };
+
             let db = db::get_db();
             let mut statement = db
-                .prepare(
+                .prepare(format!(
                     "
-                    SELECT question, answer, difficulty
+                    SELECT {}
                     FROM flashcards
-                    WHERE topic = ? AND user_id = ?
-                    ORDER BY difficulty
+                    WHERE user_id = ? {}
                                 ",
-                )
+                    FlashCardData::COLUMNS,
+                    topic_filter
+                ))
                 .unwrap();
 
-            statement.bind((1, info.topic.as_str())).unwrap();
             statement
-                .bind((2, info.user_id.to_string().as_str()))
+                .bind((1, info.user_id.to_string().as_str()))
                 .unwrap();
+            for (i, topic) in topics.iter().flatten().enumerate() {
+                statement.bind((i + 2, *topic)).unwrap();
+            }
 
             let mut rows: Vec<FlashCardData> = Vec::new();
 
             while let Ok(State::Row) = statement.next() {
-                rows.push(FlashCardData {
-                    question: statement.read::<String, _>("question").unwrap(),
-                    answer: statement.read::<String, _>("question").unwrap(),
-                    difficulty: statement.read::<i64, _>("difficulty").unwrap(),
-                });
-                info!("hello");
+                rows.push(FlashCardData::read(&statement).unwrap());
             }
 
             rows
         };
 
+        // mismatched answers are drawn from the whole deck, not only the cards asked
+        let pool = match info.mode {
+            QuizMode::TrueFalse => cards.clone(),
+            _ => Vec::new(),
+        };
+
+        let cards = match info.mode {
+            QuizMode::Exam => selection::sample_by_difficulty(
+                cards,
+                info.question_count
+                    .unwrap_or(constants::EXAM_DEFAULT_QUESTIONS),
+                &mut rand::rng(),
+            ),
+            _ if info.shuffle => {
+                let mut cards = selection::shuffle(cards, info.seed);
+                cards.truncate(info.question_count.unwrap_or(usize::MAX));
+                cards
+            }
+            _ => {
+                let stats = selection::load_stats(info.user_id).unwrap_or_else(|e| {
+                    warn!("failed to load card stats of {}: {}", info.user_id, e);
+                    HashMap::new()
+                });
+                let mut cards = info.order.strategy().order(cards, &stats);
+                cards.truncate(info.question_count.unwrap_or(usize::MAX));
+                cards
+            }
+        };
+
+        let cards = match info.mode {
+            QuizMode::TrueFalse => selection::true_false_statements(cards, &pool, &mut rand::rng()),
+            _ => cards,
+        };
+
+        Self::from_cards(info, cards)
+    }
+
+    /// Starts a quiz over the given cards, creating its report so answers can be logged.
+    /// A quiz without cards ends right away and gets no report.
+    pub fn from_cards(info: QuizData, cards: Vec<FlashCardData>) -> Self {
+        let quiz_id = if cards.is_empty() {
+            None
+        } else {
+            match create_quiz_report(&info, cards.len()) {
+                Ok(id) => Some(id),
+                Err(e) => {
+                    warn!("failed to create quiz report: {}", e);
+                    None
+                }
+            }
+        };
+
         Self {
-            info: info,
+            info,
             current: 0,
-            score: 0,
+            score: 0.0,
             total_questions: cards.len(),
             answered_questions: 0,
-            cards: cards,
+            skipped_questions: 0,
+            cards,
             new: true,
+            revealed: false,
+            ratings: [0; 4],
+            hints_used: 0,
+            quiz_id,
+            asked_at: None,
+            last_answer_id: None,
+            timed_out_questions: 0,
+            total_response_time: Duration::ZERO,
+            started_at: None,
+            sprint_spent: Duration::ZERO,
+        }
+    }
+
+    /// Restores a stopped quiz of `user_id` at the question it was left at, marking it as
+    /// running so that it cannot be resumed twice. Without a `quiz_id`, the most recent
+    /// stopped quiz is picked.
+    pub fn resume(user_id: u64, quiz_id: Option<i64>) -> Result<Option<Self>, sqlite::Error> {
+        let db = db::get_db();
+
+        let mut statement = db.prepare(
+            "
+            SELECT quiz_id, topic, mode, card_order, score, total_questions,
+                   answered_questions, skipped_questions, timed_out_questions, card_queue,
+                   position, time_limit_kind, time_limit_ms, time_left_ms, question_count,
+                   shuffle
+            FROM quiz_reports
+            WHERE user_id = ? AND state = 'stopped' AND card_queue IS NOT NULL
+              AND (? IS NULL OR quiz_id = ?)
+            ORDER BY quiz_id DESC
+            LIMIT 1",
+        )?;
+        statement.bind((1, user_id.to_string().as_str()))?;
+        statement.bind((2, quiz_id))?;
+        statement.bind((3, quiz_id))?;
+
+        if let State::Done = statement.next()? {
+            return Ok(None);
+        }
+
+        let quiz_id = statement.read::<i64, _>("quiz_id")?;
+        let millis = |ms: Option<i64>| ms.map(|ms| Duration::from_millis(ms.max(0) as u64));
+        let time_limit = TimeLimit::from_kind(
+            &statement.read::<String, _>("time_limit_kind")?,
+            millis(statement.read::<Option<i64>, _>("time_limit_ms")?),
+        );
+        let sprint_spent = match (
+            time_limit,
+            millis(statement.read::<Option<i64>, _>("time_left_ms")?),
+        ) {
+            (TimeLimit::Sprint(limit), Some(left)) => limit.saturating_sub(left),
+            _ => Duration::ZERO,
+        };
+        let info = QuizData {
+            user_id,
+            topic: statement
+                .read::<Option<String>, _>("topic")?
+                .unwrap_or_default(),
+            mode: QuizMode::from_key(&statement.read::<String, _>("mode")?).unwrap_or_default(),
+            order: OrderKind::from_key(&statement.read::<String, _>("card_order")?)
+                .unwrap_or_default(),
+            time_limit,
+            question_count: statement
+                .read::<Option<i64>, _>("question_count")?
+                .map(|n| n as usize),
+            shuffle: statement.read::<i64, _>("shuffle")? != 0,
+            ..QuizData::default()
+        };
+        let queue: Vec<i64> = statement
+            .read::<String, _>("card_queue")?
+            .split(',')
+            .filter_map(|id| id.parse().ok())
+            .collect();
+        let position = statement.read::<Option<i64>, _>("position")?.unwrap_or(0) as usize;
+        let score = statement.read::<Option<f64>, _>("score")?.unwrap_or(0.0);
+        let total_questions = statement
+            .read::<Option<i64>, _>("total_questions")?
+            .unwrap_or(0) as usize;
+        let answered_questions = statement
+            .read::<Option<i64>, _>("answered_questions")?
+            .unwrap_or(0) as usize;
+        let skipped_questions = statement.read::<i64, _>("skipped_questions")? as usize;
+        let timed_out_questions = statement.read::<i64, _>("timed_out_questions")? as usize;
+
+        // only the queued cards are loaded, along with the rest of their topics when decoys
+        // are drawn for true/false statements
+        let mut ids = queue.clone();
+        ids.sort_unstable();
+        ids.dedup();
+        let placeholders = vec!["?"; ids.len()].join(", ");
+        let filter = match info.mode {
+            QuizMode::TrueFalse => format!(
+                "topic IN (SELECT topic FROM flashcards WHERE card_id IN ({}))",
+                placeholders
+            ),
+            _ => format!("card_id IN ({})", placeholders),
+        };
+        let mut cards: Vec<FlashCardData> = Vec::new();
+        let mut statement = db.prepare(format!(
+            "SELECT {} FROM flashcards WHERE user_id = ? AND {}",
+            FlashCardData::COLUMNS,
+            filter
+        ))?;
+        statement.bind((1, user_id.to_string().as_str()))?;
+        for (i, id) in ids.iter().enumerate() {
+            statement.bind((i + 2, *id))?;
+        }
+        while let State::Row = statement.next()? {
+            cards.push(FlashCardData::read(&statement)?);
+        }
+        let by_id: HashMap<i64, &FlashCardData> = cards
+            .iter()
+            .filter_map(|card| Some((card.card_id?, card)))
+            .collect();
+
+        // cards deleted since the quiz was stopped are dropped from the queue,
+        // a card may appear several times when it was missed in an adaptive quiz
+        let mut current = position;
+        let mut ordered = Vec::with_capacity(queue.len());
+        for (i, id) in queue.iter().enumerate() {
+            match by_id.get(id) {
+                Some(card) => ordered.push((*card).clone()),
+                None if i < position => current -= 1,
+                None => {}
+            }
+        }
+        // statements are not stored, new ones are drawn for the cards left
+        if info.mode == QuizMode::TrueFalse {
+            ordered = selection::true_false_statements(ordered, &cards, &mut rand::rng());
+        }
+
+        // in recall mode the logged answers are the ratings given so far
+        let mut ratings = [0; 4];
+        if info.mode == QuizMode::Recall {
+            let mut statement = db.prepare(
+                "SELECT given_answer, COUNT(*) AS n FROM quiz_answers
+                WHERE quiz_id = ? AND given_answer IS NOT NULL GROUP BY given_answer",
+            )?;
+            statement.bind((1, quiz_id))?;
+            while let State::Row = statement.next()? {
+                let given = statement.read::<String, _>("given_answer")?;
+                if let Some(rating) = Rating::ALL.into_iter().find(|r| r.label() == given) {
+                    ratings[rating as usize] = statement.read::<i64, _>("n")? as usize;
+                }
+            }
+        }
+
+        // the average response time covers the answers given before the quiz was stopped
+        let mut statement = db.prepare(
+            "SELECT COALESCE(SUM(response_time_ms), 0) AS ms FROM quiz_answers
+            WHERE quiz_id = ? AND given_answer IS NOT NULL",
+        )?;
+        statement.bind((1, quiz_id))?;
+        statement.next()?;
+        let total_response_time = Duration::from_millis(statement.read::<i64, _>("ms")? as u64);
+
+        let mut statement =
+            db.prepare("UPDATE quiz_reports SET state = ? WHERE quiz_id = ? AND state = ?")?;
+        statement.bind((1, QuizState::Running.key()))?;
+        statement.bind((2, quiz_id))?;
+        statement.bind((3, QuizState::Stopped.key()))?;
+        statement.next()?;
+        if db.change_count() == 0 {
+            return Ok(None);
         }
+
+        Ok(Some(Self {
+            info,
+            current,
+            score,
+            total_questions,
+            answered_questions,
+            skipped_questions,
+            cards: ordered,
+            new: false,
+            revealed: false,
+            ratings,
+            hints_used: 0,
+            quiz_id: Some(quiz_id),
+            asked_at: None,
+            last_answer_id: None,
+            timed_out_questions,
+            total_response_time,
+            started_at: None,
+            sprint_spent,
+        }))
     }
 
     pub fn get_question(&mut self) -> Result<Option<&FlashCardData>, sqlite::Error> {
         if self.current >= self.cards.len() {
-            return match self.save_quiz_result() {
+            return match self.save_quiz_result(QuizState::Finished) {
                 Ok(_) => Ok(None),
                 Err(e) => Err(e),
             };
         }
-        let q = Ok(self.cards.get(self.current));
         self.current += 1;
-        q
+        self.revealed = false;
+        self.hints_used = 0;
+        self.asked_at = Some(Instant::now());
+
+        // keeps the stored progress current, so the quiz can be resumed after a restart
+        if let Err(e) = self.save_quiz_result(QuizState::Running) {
+            warn!("failed to save progress of quiz {:?}: {}", self.quiz_id, e);
+        }
+
+        Ok(self.cards.get(self.current - 1))
+    }
+
+    /// Number of questions asked so far, the current one included
+    pub fn get_position(&self) -> usize {
+        self.current
+    }
+
+    /// The card most recently returned by `get_question`
+    pub fn current_card(&self) -> Option<&FlashCardData> {
+        self.current.checked_sub(1).and_then(|i| self.cards.get(i))
     }
 
     pub fn check_answer(&mut self, input: &str) -> bool {
-        let correct_answer: &String = &self.cards.get(self.current - 1).unwrap().answer;
-        let is_correct = input == correct_answer;
-        self.answered_questions += 1;
-        if is_correct {
-            self.score += 1;
+        let card = self.cards.get(self.current - 1).unwrap();
+        let is_correct = card.is_correct(input);
+        let rating = if is_correct {
+            Rating::Good
+        } else {
+            Rating::Again
+        };
+        // a true/false warm-up is too easy to count as a review of the card
+        if let (Some(card_id), false) = (card.card_id, self.info.mode == QuizMode::TrueFalse) {
+            if let Err(e) = scheduling::record_review(card_id, rating) {
+                warn!("failed to update schedule of card {}: {}", card_id, e);
+            }
+        }
+
+        let credit = if is_correct { self.hint_credit() } else { 0.0 };
+        self.record_answer(Some(input), Some(is_correct), credit, false);
+        if !is_correct {
+            self.requeue_current();
         }
+
+        self.answered_questions += 1;
+        self.score += credit;
         is_correct
     }
 
-    pub fn save_quiz_result(&mut self) -> Result<(), sqlite::Error> {
+    /// Skips the current question without answering it, returning the skipped card
+    pub fn skip(&mut self) -> Option<&FlashCardData> {
+        if self.new || self.current_card().is_none() {
+            return None;
+        }
+        self.record_answer(None, None, 0.0, false);
+        self.skipped_questions += 1;
+        self.current_card()
+    }
+
+    /// Marks the current question as unanswered because its time ran out
+    pub fn time_out(&mut self) -> Option<&FlashCardData> {
+        if self.new || self.current_card().is_none() {
+            return None;
+        }
+        self.record_answer(None, None, 0.0, true);
+        self.timed_out_questions += 1;
+        self.current_card()
+    }
+
+    /// When the current question was asked, identifying it for its timeout
+    pub fn asked_at(&self) -> Option<Instant> {
+        self.asked_at
+    }
+
+    pub fn get_time_limit(&self) -> TimeLimit {
+        self.info.time_limit
+    }
+
+    /// Starts the clock of a sprint, returning the time left and start the first time only
+    pub fn start_clock(&mut self) -> Option<(Duration, Instant)> {
+        match (self.info.time_limit, self.started_at) {
+            (TimeLimit::Sprint(limit), None) => {
+                let now = Instant::now();
+                self.started_at = Some(now);
+                Some((limit.saturating_sub(self.sprint_spent), now))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn started_at(&self) -> Option<Instant> {
+        self.started_at
+    }
+
+    /// Time left before the end of a sprint
+    pub fn sprint_remaining(&self) -> Option<Duration> {
+        let elapsed = self.started_at.map(|s| s.elapsed()).unwrap_or_default();
+        match self.info.time_limit {
+            TimeLimit::Sprint(limit) => Some(limit.saturating_sub(self.sprint_spent + elapsed)),
+            _ => None,
+        }
+    }
+
+    /// Id of the report row of this quiz, if it could be created
+    pub fn get_quiz_id(&self) -> Option<i64> {
+        self.quiz_id
+    }
+
+    /// Id of the `quiz_answers` row logged for the most recent answer
+    pub fn last_answer_id(&self) -> Option<i64> {
+        self.last_answer_id
+    }
+
+    // Logs the outcome of the current question; a missing answer means it was skipped or timed out
+    fn record_answer(
+        &mut self,
+        given: Option<&str>,
+        is_correct: Option<bool>,
+        credit: f64,
+        timed_out: bool,
+    ) {
+        let response_time = self.asked_at.map(|t| t.elapsed()).unwrap_or_default();
+        if given.is_some() {
+            self.total_response_time += response_time;
+        }
+
+        let (quiz_id, card) = match (self.quiz_id, self.current_card()) {
+            (Some(id), Some(card)) => (id, card),
+            _ => return,
+        };
+        let response_time_ms = response_time.as_millis() as i64;
+
+        let db = db::get_db();
+        let result = db
+            .prepare(
+                "
+                INSERT INTO quiz_answers (
+                    quiz_id,
+                    card_id,
+                    position,
+                    question,
+                    reference_answer,
+                    given_answer,
+                    is_correct,
+                    skipped,
+                    credit,
+                    response_time_ms,
+                    hints_used,
+                    timed_out,
+                    choices
+                ) VALUES (?,?,?,?,?,?,?,?,?,?,?,?,?)
+                RETURNING answer_id",
+            )
+            .and_then(|mut statement| {
+                statement.bind((1, quiz_id))?;
+                statement.bind((2, card.card_id))?;
+                statement.bind((3, self.current as i64))?;
+                statement.bind((4, card.question.as_str()))?;
+                statement.bind((5, card.answer.as_str()))?;
+                statement.bind((6, given))?;
+                statement.bind((7, is_correct.map(i64::from)))?;
+                statement.bind((8, i64::from(given.is_none() && !timed_out)))?;
+                statement.bind((9, credit))?;
+                statement.bind((10, response_time_ms))?;
+                statement.bind((11, self.hints_used as i64))?;
+                statement.bind((12, i64::from(timed_out)))?;
+                statement.bind((13, card.kind.choices().as_deref()))?;
+                statement.next()?;
+                statement.read::<i64, _>("answer_id")
+            });
+
+        self.last_answer_id = match result {
+            Ok(id) => Some(id),
+            Err(e) => {
+                warn!("failed to log answer of quiz {}: {}", quiz_id, e);
+                None
+            }
+        };
+    }
+
+    // Credit left for the current question after the hints taken so far
+    fn hint_credit(&self) -> f64 {
+        (1.0 - HINT_PENALTY * self.hints_used as f64).max(0.0)
+    }
+
+    /// Level (1 to `MAX_HINTS`) of the next hint available for the current question
+    pub fn next_hint(&self) -> Option<usize> {
+        if matches!(self.info.mode, QuizMode::Exam | QuizMode::TrueFalse)
+            || self.revealed
+            || self.current_card().is_none()
+            || self.hints_used >= MAX_HINTS
+        {
+            return None;
+        }
+        Some(self.hints_used + 1)
+    }
+
+    /// Charges a delivered hint of `level` to the question at `position`, returning false
+    /// if the quiz has moved on or the hint was already charged
+    pub fn use_hint(&mut self, position: usize, level: usize) -> bool {
+        if position != self.current || self.next_hint() != Some(level) {
+            return false;
+        }
+        self.hints_used = level;
+        true
+    }
+
+    /// Marks the answer of the current card as shown, returning false if it already was
+    pub fn reveal(&mut self) -> bool {
+        if self.revealed || self.current_card().is_none() {
+            return false;
+        }
+        self.revealed = true;
+        true
+    }
+
+    /// Records a self-graded recall of the current card, which must have been revealed
+    pub fn rate(&mut self, rating: Rating) -> bool {
+        if !self.revealed {
+            return false;
+        }
+        let card_id = match self.current_card() {
+            Some(card) => card.card_id,
+            None => return false,
+        };
+        if let Some(card_id) = card_id {
+            if let Err(e) = scheduling::record_review(card_id, rating) {
+                warn!("failed to update schedule of card {}: {}", card_id, e);
+            }
+        }
+
+        let credit = rating.credit() * self.hint_credit();
+        self.record_answer(
+            Some(rating.label()),
+            Some(rating.credit() >= 1.0),
+            credit,
+            false,
+        );
+        if rating.credit() < 1.0 {
+            self.requeue_current();
+        }
+
+        self.revealed = false;
+        self.answered_questions += 1;
+        self.score += credit;
+        self.ratings[rating as usize] += 1;
+        true
+    }
+
+    /// Whether the quiz runs on generated cards rather than stored ones
+    pub fn is_practice(&self) -> bool {
+        self.cards.iter().any(|c| c.card_id.is_none())
+    }
+
+    /// Asks the current card again a few questions later if the ordering strategy wants it
+    fn requeue_current(&mut self) {
+        let gap = match self.info.order.strategy().reinsert_gap() {
+            Some(gap) => gap,
+            None => return,
+        };
+        let card = match self.current_card() {
+            Some(card) => card.clone(),
+            None => return,
+        };
+        // the card is asked once, then repeated at most MAX_REPEATS times
+        let asked = self
+            .cards
+            .iter()
+            .filter(|c| c.card_id == card.card_id && c.question == card.question)
+            .count();
+        if asked > MAX_REPEATS {
+            return;
+        }
+
+        // a repeat is one more question, for the progress and the score alike
+        let index = (self.current + gap).min(self.cards.len());
+        self.cards.insert(index, card);
+        self.total_questions += 1;
+    }
+
+    /// Stores the progress of the quiz; a stopped quiz can be resumed later
+    pub fn save_quiz_result(&mut self, state: QuizState) -> Result<(), sqlite::Error> {
+        if self.cards.is_empty() {
+            return Ok(());
+        }
+        let quiz_id = match self.quiz_id {
+            Some(id) => id,
+            None => create_quiz_report(&self.info, self.total_questions)?,
+        };
+        self.quiz_id = Some(quiz_id);
+
+        // generated cards are not stored, so a practice quiz cannot be resumed
+        let card_queue = self
+            .cards
+            .iter()
+            .map(|c| c.card_id.map(|id| id.to_string()))
+            .collect::<Option<Vec<_>>>()
+            .map(|ids| ids.join(","));
+        // the question on screen has not been answered yet, so it is asked again on resume
+        let position = self.current.saturating_sub(1);
+
         let db = db::get_db();
         let mut statement = db
             .prepare(
                 "
-                INSERT INTO quiz_reports (
-                    user_id,
-                    topic,
-                    score,
-                    total_questions,
-                    answered_questions
-                ) VALUES (
-                    ?,
-                    ?,
-                    ?,
-                    ?,
-                    ?
-                );",
+                UPDATE quiz_reports
+                SET score = ?,
+                    total_questions = ?,
+                    answered_questions = ?,
+                    skipped_questions = ?,
+                    timed_out_questions = ?,
+                    is_over = ?,
+                    state = ?,
+                    card_queue = ?,
+                    position = ?,
+                    time_left_ms = ?
+                WHERE quiz_id = ?",
             )
             .unwrap();
 
+        statement.bind((1, self.score)).unwrap();
+        statement
+            .bind((2, self.total_questions.to_string().as_str()))
+            .unwrap();
         statement
-            .bind((1, self.info.user_id.to_string().as_str()))
+            .bind((3, self.answered_questions.to_string().as_str()))
             .unwrap();
-        statement.bind((2, self.info.topic.as_str())).unwrap();
         statement
-            .bind((3, self.score.to_string().as_str()))
+            .bind((4, self.skipped_questions.to_string().as_str()))
             .unwrap();
         statement
-            .bind((4, self.total_questions.to_string().as_str()))
+            .bind((5, self.timed_out_questions as i64))
             .unwrap();
         statement
-            .bind((5, self.answered_questions.to_string().as_str()))
+            .bind((6, i64::from(state == QuizState::Finished)))
             .unwrap();
+        statement.bind((7, state.key())).unwrap();
+        statement.bind((8, card_queue.as_deref())).unwrap();
+        statement.bind((9, position as i64)).unwrap();
+        statement
+            .bind((
+                10,
+                self.sprint_remaining().map(|left| left.as_millis() as i64),
+            ))
+            .unwrap();
+        statement.bind((11, quiz_id)).unwrap();
 
         statement.next()?;
         Ok(())
     }
 
-    pub fn get_score(&mut self) -> usize {
+    pub fn get_score(&self) -> f64 {
         self.score
     }
 
-    pub fn get_answered(&mut self) -> usize {
+    pub fn get_answered(&self) -> usize {
         self.answered_questions
     }
 
+    pub fn get_skipped(&self) -> usize {
+        self.skipped_questions
+    }
+
+    pub fn get_timed_out(&self) -> usize {
+        self.timed_out_questions
+    }
+
+    /// Mean time taken by the answers given so far
+    pub fn average_response_time(&self) -> Option<Duration> {
+        match self.answered_questions {
+            0 => None,
+            n => Some(self.total_response_time / n as u32),
+        }
+    }
+
+    pub fn get_user_id(&self) -> u64 {
+        self.info.user_id
+    }
+
+    pub fn get_topic(&self) -> &str {
+        &self.info.topic
+    }
+
+    pub fn get_total(&self) -> usize {
+        self.total_questions
+    }
+
+    pub fn get_mode(&self) -> QuizMode {
+        self.info.mode
+    }
+
+    /// How many cards received each rating, in the order of `Rating::ALL`
+    pub fn get_ratings(&self) -> [usize; 4] {
+        self.ratings
+    }
+
     pub fn is_new(&mut self) -> bool {
         let prev = self.new;
-        if prev == true {
+        if prev {
             self.new = false
         }
         prev
     }
 }
+
+fn create_quiz_report(info: &QuizData, total_questions: usize) -> Result<i64, sqlite::Error> {
+    let db = db::get_db();
+    let mut statement = db.prepare(
+        "
+        INSERT INTO quiz_reports (
+            user_id,
+            topic,
+            score,
+            total_questions,
+            answered_questions,
+            mode,
+            card_order,
+            is_over,
+            state,
+            time_limit_kind,
+            time_limit_ms,
+            question_count,
+            shuffle
+        ) VALUES (?, ?, 0, ?, 0, ?, ?, 0, 'running', ?, ?, ?, ?)
+        RETURNING quiz_id",
+    )?;
+
+    statement.bind((1, info.user_id.to_string().as_str()))?;
+    statement.bind((2, info.topic.as_str()))?;
+    statement.bind((3, total_questions as i64))?;
+    statement.bind((4, info.mode.key()))?;
+    statement.bind((5, info.order.key()))?;
+    statement.bind((6, info.time_limit.kind()))?;
+    statement.bind((7, info.time_limit.duration().map(|d| d.as_millis() as i64)))?;
+    statement.bind((8, info.question_count.map(|n| n as i64)))?;
+    statement.bind((9, i64::from(info.shuffle)))?;
+
+    statement.next()?;
+    statement.read::<i64, _>("quiz_id")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored_card(user_id: u64, topic: &str, question: &str, answer: &str) -> FlashCardData {
+        let db = db::get_db();
+        let mut statement = db
+            .prepare(format!(
+                "INSERT INTO flashcards (user_id, question, answer, topic, difficulty)
+                VALUES (?, ?, ?, ?, 1)
+                RETURNING {}",
+                FlashCardData::COLUMNS
+            ))
+            .unwrap();
+        statement.bind((1, user_id.to_string().as_str())).unwrap();
+        statement.bind((2, question)).unwrap();
+        statement.bind((3, answer)).unwrap();
+        statement.bind((4, topic)).unwrap();
+        statement.next().unwrap();
+        FlashCardData::read(&statement).unwrap()
+    }
+
+    fn delete_card(card_id: Option<i64>) {
+        let db = db::get_db();
+        let mut statement = db
+            .prepare("DELETE FROM flashcards WHERE card_id = ?")
+            .unwrap();
+        statement.bind((1, card_id)).unwrap();
+        statement.next().unwrap();
+    }
+
+    fn quiz(user_id: u64, mode: QuizMode, cards: Vec<FlashCardData>) -> QuizManager {
+        let info = QuizData {
+            user_id,
+            topic: String::from("geo"),
+            mode,
+            ..QuizData::default()
+        };
+        let mut quiz = QuizManager::from_cards(info, cards);
+        quiz.is_new();
+        quiz
+    }
+
+    #[test]
+    fn resumes_at_the_question_left_without_deleted_cards() {
+        let user_id = 3101;
+        let cards = vec![
+            stored_card(user_id, "geo", "Capital of France?", "Paris"),
+            stored_card(user_id, "geo", "Capital of Italy?", "Rome"),
+            stored_card(user_id, "geo", "Capital of Spain?", "Madrid"),
+        ];
+        let first = cards[0].card_id;
+        let mut stopped = quiz(user_id, QuizMode::Typed, cards);
+        stopped.get_question().unwrap();
+        assert!(stopped.check_answer("Paris"));
+        stopped.get_question().unwrap();
+        stopped.save_quiz_result(QuizState::Stopped).unwrap();
+        delete_card(first);
+
+        let mut resumed = QuizManager::resume(user_id, None).unwrap().unwrap();
+        assert_eq!(resumed.get_quiz_id(), stopped.get_quiz_id());
+        assert_eq!((resumed.get_answered(), resumed.get_score()), (1, 1.0));
+        let card = resumed.get_question().unwrap().unwrap();
+        assert_eq!(card.question, "Capital of Italy?");
+
+        // a resumed quiz is running again, so it cannot be resumed twice
+        assert!(QuizManager::resume(user_id, None).unwrap().is_none());
+    }
+
+    #[test]
+    fn resumes_the_ratings_of_a_recall_quiz() {
+        let user_id = 3102;
+        let cards = vec![
+            stored_card(user_id, "geo", "Longest river?", "Nile"),
+            stored_card(user_id, "geo", "Highest mountain?", "Everest"),
+            stored_card(user_id, "geo", "Largest desert?", "Antarctica"),
+        ];
+        let mut stopped = quiz(user_id, QuizMode::Recall, cards);
+        for rating in [Rating::Good, Rating::Easy] {
+            stopped.get_question().unwrap();
+            assert!(stopped.reveal());
+            assert!(stopped.rate(rating));
+        }
+        stopped.get_question().unwrap();
+        stopped.save_quiz_result(QuizState::Stopped).unwrap();
+
+        let resumed = QuizManager::resume(user_id, stopped.get_quiz_id())
+            .unwrap()
+            .unwrap();
+        assert_eq!(resumed.get_ratings(), [0, 0, 1, 1]);
+        assert!(QuizManager::resume(user_id + 1, None).unwrap().is_none());
+    }
+
+    #[test]
+    fn asks_a_missed_card_again_a_few_questions_later() {
+        let cards: Vec<FlashCardData> = (1..=5)
+            .map(|i| FlashCardData {
+                card_id: None,
+                difficulty: 1,
+                answer: i.to_string(),
+                question: format!("{} + 0?", i),
+                topic: String::from("math"),
+                kind: CardKind::Text,
+            })
+            .collect();
+        let info = QuizData {
+            user_id: 3401,
+            order: OrderKind::Adaptive,
+            ..QuizData::default()
+        };
+        let mut quiz = QuizManager::from_cards(info, cards);
+        quiz.is_new();
+        quiz.get_question().unwrap();
+        assert!(!quiz.check_answer("7"));
+
+        let mut asked = vec![quiz.current_card().unwrap().answer.clone()];
+        while let Some(card) = quiz.get_question().unwrap() {
+            asked.push(card.answer.clone());
+        }
+        assert_eq!(asked, ["1", "2", "3", "4", "1", "5"]);
+        assert_eq!(quiz.total_questions, 6);
+    }
+
+    #[test]
+    fn repeats_a_missed_card_a_limited_number_of_times() {
+        let info = QuizData {
+            user_id: 3402,
+            order: OrderKind::Adaptive,
+            ..QuizData::default()
+        };
+        let card = FlashCardData {
+            card_id: None,
+            difficulty: 1,
+            answer: String::from("4"),
+            question: String::from("2 + 2?"),
+            topic: String::from("math"),
+            kind: CardKind::Text,
+        };
+        let mut quiz = QuizManager::from_cards(info, vec![card]);
+        quiz.is_new();
+        let mut asked = 0;
+        while quiz.get_question().unwrap().is_some() {
+            asked += 1;
+            quiz.check_answer("5");
+        }
+        assert_eq!(asked, 1 + MAX_REPEATS);
+    }
+}
@@ -0,0 +1,272 @@
+// Exponents of the SI base dimensions, in the order m, kg, s, A, K, mol, cd
+type Dimensions = [i8; 7];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Unit {
+    /// Multiplier that converts a value in this unit to SI base units
+    pub factor: f64,
+    pub dimensions: Dimensions,
+}
+
+const DIMENSIONLESS: Dimensions = [0; 7];
+
+// Symbols matched as-is, before trying to split off an SI prefix
+const SYMBOLS: &[(&str, f64, Dimensions)] = &[
+    ("m", 1.0, [1, 0, 0, 0, 0, 0, 0]),
+    ("g", 1e-3, [0, 1, 0, 0, 0, 0, 0]),
+    ("s", 1.0, [0, 0, 1, 0, 0, 0, 0]),
+    ("A", 1.0, [0, 0, 0, 1, 0, 0, 0]),
+    ("K", 1.0, [0, 0, 0, 0, 1, 0, 0]),
+    ("mol", 1.0, [0, 0, 0, 0, 0, 1, 0]),
+    ("cd", 1.0, [0, 0, 0, 0, 0, 0, 1]),
+    ("N", 1.0, [1, 1, -2, 0, 0, 0, 0]),
+    ("J", 1.0, [2, 1, -2, 0, 0, 0, 0]),
+    ("W", 1.0, [2, 1, -3, 0, 0, 0, 0]),
+    ("Pa", 1.0, [-1, 1, -2, 0, 0, 0, 0]),
+    ("Hz", 1.0, [0, 0, -1, 0, 0, 0, 0]),
+    ("C", 1.0, [0, 0, 1, 1, 0, 0, 0]),
+    ("V", 1.0, [2, 1, -3, -1, 0, 0, 0]),
+    ("Ω", 1.0, [2, 1, -3, -2, 0, 0, 0]),
+    ("ohm", 1.0, [2, 1, -3, -2, 0, 0, 0]),
+    ("F", 1.0, [-2, -1, 4, 2, 0, 0, 0]),
+    ("T", 1.0, [0, 1, -2, -1, 0, 0, 0]),
+    ("L", 1e-3, [3, 0, 0, 0, 0, 0, 0]),
+    ("l", 1e-3, [3, 0, 0, 0, 0, 0, 0]),
+    ("eV", 1.602_176_634e-19, [2, 1, -2, 0, 0, 0, 0]),
+    ("cal", 4.184, [2, 1, -2, 0, 0, 0, 0]),
+    ("bar", 1e5, [-1, 1, -2, 0, 0, 0, 0]),
+    ("atm", 101_325.0, [-1, 1, -2, 0, 0, 0, 0]),
+    ("min", 60.0, [0, 0, 1, 0, 0, 0, 0]),
+    ("h", 3600.0, [0, 0, 1, 0, 0, 0, 0]),
+    ("day", 86_400.0, [0, 0, 1, 0, 0, 0, 0]),
+    ("rad", 1.0, DIMENSIONLESS),
+    ("deg", std::f64::consts::PI / 180.0, DIMENSIONLESS),
+    ("°", std::f64::consts::PI / 180.0, DIMENSIONLESS),
+    ("%", 0.01, DIMENSIONLESS),
+];
+
+// Only these symbols accept an SI prefix ("km", "mA", "kWh" is not supported)
+const PREFIXABLE: &[&str] = &[
+    "m", "g", "s", "A", "K", "mol", "cd", "N", "J", "W", "Pa", "Hz", "C", "V", "Ω", "ohm", "F",
+    "T", "L", "l", "eV", "cal", "bar",
+];
+
+const PREFIXES: &[(&str, f64)] = &[
+    ("da", 1e1),
+    ("Y", 1e24),
+    ("Z", 1e21),
+    ("E", 1e18),
+    ("P", 1e15),
+    ("T", 1e12),
+    ("G", 1e9),
+    ("M", 1e6),
+    ("k", 1e3),
+    ("h", 1e2),
+    ("d", 1e-1),
+    ("c", 1e-2),
+    ("m", 1e-3),
+    ("µ", 1e-6),
+    ("μ", 1e-6),
+    ("u", 1e-6),
+    ("n", 1e-9),
+    ("p", 1e-12),
+    ("f", 1e-15),
+    ("a", 1e-18),
+];
+
+impl Unit {
+    pub fn dimensionless() -> Self {
+        Unit {
+            factor: 1.0,
+            dimensions: DIMENSIONLESS,
+        }
+    }
+
+    pub fn is_compatible(&self, other: &Unit) -> bool {
+        self.dimensions == other.dimensions
+    }
+
+    /// Converts `value` expressed in `self` into the equivalent value expressed in `target`
+    pub fn convert(&self, value: f64, target: &Unit) -> Option<f64> {
+        if self.is_compatible(target) {
+            Some(value * self.factor / target.factor)
+        } else {
+            None
+        }
+    }
+
+    // None when an exponent overflows, e.g. for "m^100 m^100"
+    fn pow(self, exponent: i8) -> Option<Self> {
+        let mut dimensions = self.dimensions;
+        for d in dimensions.iter_mut() {
+            *d = d.checked_mul(exponent)?;
+        }
+        Some(Unit {
+            factor: self.factor.powi(exponent as i32),
+            dimensions,
+        })
+    }
+
+    fn mul(self, other: Unit) -> Option<Self> {
+        let mut dimensions = self.dimensions;
+        for (d, o) in dimensions.iter_mut().zip(other.dimensions) {
+            *d = d.checked_add(o)?;
+        }
+        Some(Unit {
+            factor: self.factor * other.factor,
+            dimensions,
+        })
+    }
+}
+
+fn lookup_symbol(symbol: &str) -> Option<Unit> {
+    if let Some((_, factor, dimensions)) = SYMBOLS.iter().find(|(s, _, _)| *s == symbol) {
+        return Some(Unit {
+            factor: *factor,
+            dimensions: *dimensions,
+        });
+    }
+
+    PREFIXES.iter().find_map(|(prefix, scale)| {
+        let base = symbol.strip_prefix(prefix)?;
+        if !PREFIXABLE.contains(&base) {
+            return None;
+        }
+        let (_, factor, dimensions) = SYMBOLS.iter().find(|(s, _, _)| *s == base)?;
+        Some(Unit {
+            factor: factor * scale,
+            dimensions: *dimensions,
+        })
+    })
+}
+
+/// The ASCII digit or minus sign of a superscript character such as "²" or "⁻"
+pub fn superscript_to_ascii(c: char) -> Option<char> {
+    let mapped = match c {
+        '⁰' => '0',
+        '¹' => '1',
+        '²' => '2',
+        '³' => '3',
+        '⁴' => '4',
+        '⁵' => '5',
+        '⁶' => '6',
+        '⁷' => '7',
+        '⁸' => '8',
+        '⁹' => '9',
+        '⁻' => '-',
+        _ => return None,
+    };
+    Some(mapped)
+}
+
+// Parses a single factor such as "m", "s^2", "s²" or "s-1"
+fn parse_factor(factor: &str) -> Option<Unit> {
+    let mut symbol = String::new();
+    let mut exponent = String::new();
+    let mut in_exponent = false;
+
+    for c in factor.chars() {
+        if let Some(digit) = superscript_to_ascii(c) {
+            in_exponent = true;
+            exponent.push(digit);
+        } else if c == '^' {
+            in_exponent = true;
+        } else if in_exponent || c.is_ascii_digit() || (c == '-' && !symbol.is_empty()) {
+            in_exponent = true;
+            exponent.push(c);
+        } else {
+            symbol.push(c);
+        }
+    }
+
+    let exponent: i8 = if exponent.is_empty() {
+        1
+    } else {
+        exponent.parse().ok()?
+    };
+
+    lookup_symbol(&symbol)?.pow(exponent)
+}
+
+/// Parses a compound unit such as "m/s^2", "kg·m/s²", "km/h" or "N m"
+pub fn parse_unit(text: &str) -> Option<Unit> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Some(Unit::dimensionless());
+    }
+
+    let mut unit = Unit::dimensionless();
+
+    for (i, part) in text.split('/').enumerate() {
+        let sign = if i == 0 { 1 } else { -1 };
+        let factors: Vec<&str> = part
+            .split(['*', '·', '⋅', ' '])
+            .filter(|f| !f.is_empty())
+            .collect();
+
+        if factors.is_empty() {
+            return None;
+        }
+
+        for factor in factors {
+            unit = unit.mul(parse_factor(factor)?.pow(sign)?)?;
+        }
+    }
+
+    Some(unit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_compound_units() {
+        let newton = parse_unit("N").unwrap();
+        assert_eq!(parse_unit("kg·m/s²").unwrap().dimensions, newton.dimensions);
+        assert_eq!(
+            parse_unit("kg*m*s^-2").unwrap().dimensions,
+            newton.dimensions
+        );
+        assert_eq!(
+            parse_unit("kg m s-2").unwrap().dimensions,
+            newton.dimensions
+        );
+        assert_eq!(parse_unit("").unwrap(), Unit::dimensionless());
+    }
+
+    #[test]
+    fn converts_prefixed_units() {
+        let kmh = parse_unit("km/h").unwrap();
+        let ms = parse_unit("m/s").unwrap();
+        let converted = kmh.convert(36.0, &ms).unwrap();
+        assert!((converted - 10.0).abs() < 1e-9);
+
+        let ml = parse_unit("mL").unwrap();
+        let l = parse_unit("L").unwrap();
+        assert!((ml.convert(250.0, &l).unwrap() - 0.25).abs() < 1e-12);
+    }
+
+    #[test]
+    fn refuses_incompatible_conversions() {
+        let metre = parse_unit("m").unwrap();
+        let second = parse_unit("s").unwrap();
+        assert_eq!(metre.convert(1.0, &second), None);
+    }
+
+    #[test]
+    fn rejects_unknown_and_malformed_units() {
+        assert!(parse_unit("furlong").is_none());
+        assert!(parse_unit("kWh").is_none());
+        assert!(parse_unit("m/").is_none());
+        assert!(parse_unit("m^x").is_none());
+    }
+
+    #[test]
+    fn rejects_overflowing_exponents() {
+        assert!(parse_unit("m^100 m^100").is_none());
+        assert!(parse_unit("m^127 m").is_none());
+        assert!(parse_unit("s/m^-128").is_none());
+        assert!(parse_unit("m^127/m^127").is_some());
+    }
+}
@@ -0,0 +1,272 @@
+use crate::db;
+use crate::llm::ModelPrice;
+use crate::requests::Usage;
+
+use sqlite::State;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Logs the tokens of one answered request in `llm_usage`
+pub fn record(
+    user_id: Option<u64>,
+    action: &str,
+    model: &str,
+    usage: Usage,
+    latency: Duration,
+) -> Result<(), sqlite::Error> {
+    let db = db::get_db();
+    let mut statement = db.prepare(
+        "INSERT INTO llm_usage (user_id, action, model, prompt_tokens, completion_tokens, latency_ms)
+        VALUES (?, ?, ?, ?, ?, ?)",
+    )?;
+    statement.bind((1, user_id.map(|id| id.to_string()).as_deref()))?;
+    statement.bind((2, action))?;
+    statement.bind((3, model))?;
+    statement.bind((4, usage.prompt_tokens as i64))?;
+    statement.bind((5, usage.completion_tokens as i64))?;
+    statement.bind((6, latency.as_millis() as i64))?;
+    statement.next()?;
+    Ok(())
+}
+
+/// How the usage of a report is grouped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Breakdown {
+    /// The actions of a single user
+    Action(u64),
+    User,
+    Model,
+}
+
+/// Usage of one group of a report
+pub struct UsageLine {
+    pub label: String,
+    pub requests: i64,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub average_latency_ms: i64,
+    /// Cost of the requests to models with a price
+    pub cost: f64,
+    /// Whether some requests went to models without a price
+    pub unpriced: bool,
+}
+
+impl UsageLine {
+    pub fn render(&self) -> String {
+        format!(
+            "• {}: {} requests, {} + {} tokens, {} ms on average, ${:.4}{}",
+            self.label,
+            self.requests,
+            self.prompt_tokens,
+            self.completion_tokens,
+            self.average_latency_ms,
+            self.cost,
+            if self.unpriced { "+" } else { "" }
+        )
+    }
+}
+
+/// Usage of the last `days` days, most expensive groups first
+pub fn load_usage(
+    breakdown: Breakdown,
+    days: u32,
+    prices: &HashMap<String, ModelPrice>,
+) -> Result<Vec<UsageLine>, sqlite::Error> {
+    let (label, filter) = match breakdown {
+        Breakdown::Action(_) => ("llm_usage.action", "AND llm_usage.user_id = ?"),
+        Breakdown::User => (
+            "COALESCE(users.username, CAST(llm_usage.user_id AS TEXT), 'unknown')",
+            "",
+        ),
+        Breakdown::Model => ("llm_usage.model", ""),
+    };
+
+    let db = db::get_db();
+    let mut statement = db.prepare(format!(
+        "SELECT {} AS label, llm_usage.model AS model, COUNT(*) AS requests,
+            SUM(prompt_tokens) AS prompt_tokens, SUM(completion_tokens) AS completion_tokens,
+            SUM(latency_ms) AS latency_ms
+        FROM llm_usage LEFT JOIN users ON users.id = llm_usage.user_id
+        WHERE llm_usage.created_at >= datetime('now', ?) {}
+        GROUP BY label, llm_usage.model",
+        label, filter
+    ))?;
+    statement.bind((1, format!("-{} days", days).as_str()))?;
+    if let Breakdown::Action(user_id) = breakdown {
+        statement.bind((2, user_id.to_string().as_str()))?;
+    }
+
+    // rows are per model, so that every model is priced on its own
+    let mut lines: Vec<UsageLine> = Vec::new();
+    let mut total_latency: HashMap<String, i64> = HashMap::new();
+    while let State::Row = statement.next()? {
+        let label = statement.read::<String, _>("label")?;
+        let model = statement.read::<String, _>("model")?;
+        let requests = statement.read::<i64, _>("requests")?;
+        let prompt_tokens = statement.read::<i64, _>("prompt_tokens")?;
+        let completion_tokens = statement.read::<i64, _>("completion_tokens")?;
+        *total_latency.entry(label.clone()).or_default() +=
+            statement.read::<i64, _>("latency_ms")?;
+
+        let price = prices.get(&model);
+        let cost = price.map_or(0.0, |p| {
+            p.cost(prompt_tokens as u64, completion_tokens as u64)
+        });
+        match lines.iter_mut().find(|l| l.label == label) {
+            Some(line) => {
+                line.requests += requests;
+                line.prompt_tokens += prompt_tokens;
+                line.completion_tokens += completion_tokens;
+                line.cost += cost;
+                line.unpriced |= price.is_none();
+            }
+            None => lines.push(UsageLine {
+                label,
+                requests,
+                prompt_tokens,
+                completion_tokens,
+                average_latency_ms: 0,
+                cost,
+                unpriced: price.is_none(),
+            }),
+        }
+    }
+
+    for line in &mut lines {
+        line.average_latency_ms = total_latency[&line.label] / line.requests.max(1);
+    }
+    lines.sort_by(|a, b| {
+        b.cost
+            .total_cmp(&a.cost)
+            .then(b.prompt_tokens.cmp(&a.prompt_tokens))
+    });
+    Ok(lines)
+}
+
+/// Usage report as sent by `/usage`
+pub fn render_usage(title: &str, lines: &[UsageLine]) -> String {
+    if lines.is_empty() {
+        return format!("{}\nNo AI request in this period.", title);
+    }
+
+    let mut text = format!("{}\n", title);
+    for line in lines {
+        text.push_str(&line.render());
+        text.push('\n');
+    }
+    text.push_str(&format!(
+        "Total: {} requests, ${:.4}",
+        lines.iter().map(|l| l.requests).sum::<i64>(),
+        lines.iter().map(|l| l.cost).sum::<f64>()
+    ));
+    if lines.iter().any(|l| l.unpriced) {
+        text.push_str("\n+ some models have no price in config.toml and are not counted");
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(prompt_tokens: u64, completion_tokens: u64) -> Usage {
+        Usage {
+            prompt_tokens,
+            completion_tokens,
+        }
+    }
+
+    #[test]
+    fn sums_the_usage_of_each_action_and_prices_each_model() {
+        let user_id = 4801;
+        let latency = Duration::from_millis;
+        record(
+            Some(user_id),
+            "define",
+            "priced-model",
+            tokens(1_000_000, 0),
+            latency(100),
+        )
+        .unwrap();
+        record(
+            Some(user_id),
+            "define",
+            "free-model",
+            tokens(10, 20),
+            latency(300),
+        )
+        .unwrap();
+        record(
+            Some(user_id),
+            "explain",
+            "priced-model",
+            tokens(100, 500_000),
+            latency(50),
+        )
+        .unwrap();
+        record(
+            Some(user_id + 1),
+            "define",
+            "priced-model",
+            tokens(1, 1),
+            latency(1),
+        )
+        .unwrap();
+
+        let prices = HashMap::from([(
+            String::from("priced-model"),
+            ModelPrice {
+                prompt: 1.0,
+                completion: 2.0,
+            },
+        )]);
+        let lines = load_usage(Breakdown::Action(user_id), 30, &prices).unwrap();
+        assert_eq!(lines.len(), 2);
+        // the most expensive group comes first
+        let (explain, define) = (&lines[0], &lines[1]);
+        assert_eq!(define.label, "define");
+        assert_eq!((define.requests, define.prompt_tokens), (2, 1_000_010));
+        assert_eq!(define.average_latency_ms, 200);
+        assert!((define.cost - 1.0).abs() < 1e-9);
+        assert!(define.unpriced && !explain.unpriced);
+        assert_eq!(
+            explain.render(),
+            "• explain: 1 requests, 100 + 500000 tokens, 50 ms on average, $1.0001"
+        );
+
+        let text = render_usage("Usage", &lines);
+        assert!(text.contains("Total: 3 requests, $2.0001"), "{}", text);
+        assert!(text.ends_with("are not counted"), "{}", text);
+        assert!(render_usage("Usage", &[]).ends_with("No AI request in this period."));
+    }
+
+    #[test]
+    fn groups_the_usage_by_model() {
+        record(
+            None,
+            "summarize",
+            "grouped-model",
+            tokens(7, 3),
+            Duration::ZERO,
+        )
+        .unwrap();
+        record(
+            Some(4802),
+            "define",
+            "grouped-model",
+            tokens(3, 7),
+            Duration::ZERO,
+        )
+        .unwrap();
+
+        let lines = load_usage(Breakdown::Model, 30, &HashMap::new()).unwrap();
+        let line = lines.iter().find(|l| l.label == "grouped-model").unwrap();
+        assert_eq!(
+            (line.requests, line.prompt_tokens, line.completion_tokens),
+            (2, 10, 10)
+        );
+        assert!(load_usage(Breakdown::Action(4803), 30, &HashMap::new())
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -1,14 +1,40 @@
-pub fn escape_md_v2(text: &str) -> String {
-    let specials = r"_*\[\]()~`>#+\-=|{}.!";
+use std::time::Duration;
 
-    let mut new = String::with_capacity(text.len());
-
-    for c in text.chars() {
-        if specials.contains(c) {
-            new.push('\\');
-        }
-        new.push(c);
+/// First hint level: the first letter of the answer
+pub fn first_letter_hint(answer: &str) -> String {
+    match answer.trim().chars().next() {
+        Some(c) => format!("The answer starts with \"{}\"", c),
+        None => String::from("The answer is empty"),
     }
+}
+
+/// Second hint level: the answer with every letter but the first replaced by an underscore
+pub fn letter_count_hint(answer: &str) -> String {
+    let answer = answer.trim();
+    let letters = answer.chars().filter(|c| c.is_alphanumeric()).count();
+
+    let mask: String = answer
+        .chars()
+        .enumerate()
+        .map(|(i, c)| {
+            if i == 0 || !c.is_alphanumeric() {
+                c.to_string()
+            } else {
+                String::from("_")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
 
-    new
+    format!("{} ({} letters)", mask, letters)
+}
+
+/// Formats a duration as "45s", "5m" or "2m 30s"
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match (secs / 60, secs % 60) {
+        (0, s) => format!("{}s", s),
+        (m, 0) => format!("{}m", m),
+        (m, s) => format!("{}m {}s", m, s),
+    }
 }